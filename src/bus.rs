@@ -1,3 +1,21 @@
+pub mod apu;
+pub mod display;
+pub mod palette;
+pub mod rng;
+pub mod rtc;
+pub mod sprites;
+
+use std::cell::RefCell;
+
+use crate::cpu::MemLayout;
+
+use self::apu::Apu;
+use self::display::{Display, GraphicsMode};
+use self::palette::Palette;
+use self::rng::Rng;
+use self::rtc::Rtc;
+use self::sprites::{Sprites, SPRITE_COUNT};
+
 macro_rules! box_array {
     ($val:expr; $len:expr) => {{
         fn box_from_vec<T>(vec: Vec<T>) -> Box<[T; $len]> {
@@ -10,6 +28,21 @@ macro_rules! box_array {
     }};
 }
 
+const VGA_WRITES_LEN: usize =
+    (MemLayout::VGA_BUF1_END - MemLayout::VGA_BUF_START + 1) as usize / u64::BITS as usize;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Access {
+    pub addr: u16,
+    pub kind: AccessKind,
+}
+
 struct OperativeMemory {
     ram: Box<[u8; u16::MAX as usize + 1]>,
 }
@@ -28,7 +61,7 @@ trait StorageDevice {
 
     fn read_word(&self, addr: u16) -> u16 {
         let lo = self.read_byte(addr);
-        let hi = self.read_byte(addr + 1);
+        let hi = self.read_byte(addr.wrapping_add(1));
         u16::from_le_bytes([lo, hi])
     }
 
@@ -36,7 +69,7 @@ trait StorageDevice {
         match data.to_le_bytes() {
             [lo, hi] => {
                 self.write_byte(addr, lo);
-                self.write_byte(addr + 1, hi);
+                self.write_byte(addr.wrapping_add(1), hi);
             }
         }
     }
@@ -54,6 +87,21 @@ impl StorageDevice for OperativeMemory {
 
 pub struct Bus {
     operative_memory: OperativeMemory,
+    exit_code: Option<u8>,
+    rng: Rng,
+    rtc: Rtc,
+    apu: Apu,
+    palette: Palette,
+    display: Display,
+    sprites: Sprites,
+    // One bit per VGA Buffer address (of both pages) written since the last take_vga_writes
+    vga_writes: [u64; VGA_WRITES_LEN],
+    // Sprite and sprite-background collision masks, latched at the start of a vertical blank
+    collisions: (u8, u8),
+    cycles: u64,
+    // CPU memory accesses since the last take_accesses, recorded only while tracking is on
+    accesses: RefCell<Vec<Access>>,
+    track_accesses: bool,
 }
 
 impl Default for Bus {
@@ -66,22 +114,259 @@ impl Bus {
     pub fn new() -> Self {
         Bus {
             operative_memory: OperativeMemory::new(),
+            exit_code: None,
+            rng: Rng::default(),
+            rtc: Rtc::default(),
+            apu: Apu::default(),
+            palette: Palette::default(),
+            display: Display::default(),
+            sprites: Sprites::default(),
+            vga_writes: [0; VGA_WRITES_LEN],
+            collisions: (0, 0),
+            cycles: 0,
+            accesses: RefCell::new(vec![]),
+            track_accesses: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.operative_memory = OperativeMemory::new();
+        self.reset_devices();
+    }
+
+    // Puts every device back into its power-on state, leaving RAM alone
+    pub fn reset_devices(&mut self) {
+        self.exit_code = None;
+        self.rng.reset();
+        self.rtc.reset();
+        self.apu.reset();
+        self.palette.reset();
+        self.display.reset();
+        self.sprites.reset();
+        self.vga_writes = [0; VGA_WRITES_LEN];
+        self.collisions = (0, 0);
+        self.cycles = 0;
+        self.accesses.borrow_mut().clear();
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.apu.tick(cycles, self.cycles);
+
+        let was_in_vblank = self.display.in_vblank();
+        self.display.tick(cycles);
+        if !was_in_vblank && self.display.in_vblank() {
+            self.collisions = self.sprite_collisions();
+        }
+    }
+
+    pub fn rng_mut(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub fn rtc_mut(&mut self) -> &mut Rtc {
+        &mut self.rtc
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut Display {
+        &mut self.display
+    }
+
+    // Addresses of the VGA Buffer pages written since the last call
+    pub fn take_vga_writes(&mut self) -> Vec<u16> {
+        let mut addrs = vec![];
+        for (word_idx, word) in self.vga_writes.iter_mut().enumerate() {
+            while *word != 0 {
+                let bit = word.trailing_zeros();
+                *word &= *word - 1;
+                addrs.push(MemLayout::VGA_BUF_START + (word_idx as u32 * u64::BITS + bit) as u16);
+            }
+        }
+
+        addrs
+    }
+
+    pub fn sprites(&self) -> &Sprites {
+        &self.sprites
+    }
+
+    // Color of the topmost sprite pixel at (x, y) of the VGA Buffer, sprite 0 being on top
+    pub fn sprite_pixel(&self, x: u32, y: u32) -> Option<u8> {
+        let coverage = self.sprites.coverage(x, y, &self.operative_memory);
+        (0..SPRITE_COUNT)
+            .find(|idx| coverage & (1 << idx) != 0)
+            .map(|idx| self.sprites.sprite(idx).color)
+    }
+
+    // Masks of sprites touching another sprite and of sprites touching a non-black VGA pixel
+    fn sprite_collisions(&self) -> (u8, u8) {
+        let side = GraphicsMode::Vga.resolution().0;
+        let mut collisions = (0, 0);
+        for y in 0..side {
+            for x in 0..side {
+                let coverage = self.sprites.coverage(x, y, &self.operative_memory);
+                if coverage.count_ones() > 1 {
+                    collisions.0 |= coverage;
+                }
+
+                let addr = self.display.vga_page_start() + (y * side + x) as u16;
+                if self.operative_memory.read_byte(addr) != 0 {
+                    collisions.1 |= coverage;
+                }
+            }
+        }
+
+        collisions
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+
+    // Reads memory like the CPU would, but without side effects: the Random Number Generator
+    // and the Speaker read as 0, and the Real-Time Clock isn't latched. Meant for debuggers and dumps.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            MemLayout::RNG_PORT | MemLayout::SPEAKER => 0,
+            MemLayout::RTC_START..=MemLayout::RTC_END => self.rtc.peek(addr - MemLayout::RTC_START),
+            _ => self.read_byte(addr),
+        }
+    }
+
+    // Makes the bus record the addresses the CPU reads and writes, for watchpoints
+    pub fn set_access_tracking(&mut self, enabled: bool) {
+        self.track_accesses = enabled;
+        if !enabled {
+            self.accesses.borrow_mut().clear();
+        }
+    }
+
+    // Memory accesses since the last call, in order
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        self.accesses.take()
+    }
+
+    fn record(&self, addr: u16, kind: AccessKind, len: u16) {
+        if self.track_accesses {
+            let mut accesses = self.accesses.borrow_mut();
+            for offset in 0..len {
+                let addr = addr.wrapping_add(offset);
+                accesses.push(Access { addr, kind });
+            }
         }
     }
 
     pub fn mem_read_byte(&self, addr: u16) -> u8 {
-        self.operative_memory.read_byte(addr)
+        self.record(addr, AccessKind::Read, 1);
+        self.read_byte(addr)
     }
 
     pub fn mem_write_byte(&mut self, addr: u16, data: u8) {
-        self.operative_memory.write_byte(addr, data);
+        self.record(addr, AccessKind::Write, 1);
+        self.write_byte(addr, data);
     }
 
     pub fn mem_read_word(&self, addr: u16) -> u16 {
-        self.operative_memory.read_word(addr)
+        self.record(addr, AccessKind::Read, 2);
+        self.read_word(addr)
     }
 
     pub fn mem_write_word(&mut self, addr: u16, data: u16) {
-        self.operative_memory.write_word(addr, data);
+        self.record(addr, AccessKind::Write, 2);
+        self.write_word(addr, data);
+    }
+}
+
+impl StorageDevice for Bus {
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            MemLayout::EXIT_PORT => self.exit_code.unwrap_or(0),
+            MemLayout::RNG_PORT => self.rng.read_byte(addr),
+            MemLayout::RTC_START..=MemLayout::RTC_END => {
+                self.rtc.read_byte(addr - MemLayout::RTC_START)
+            }
+            MemLayout::AUDIO_START..=MemLayout::AUDIO_END => {
+                self.apu.read_byte(addr - MemLayout::AUDIO_START)
+            }
+            MemLayout::SPEAKER => {
+                self.apu.toggle_speaker(self.cycles);
+                0
+            }
+            MemLayout::PALETTE_START..=MemLayout::PALETTE_END => {
+                self.palette.read_byte(addr - MemLayout::PALETTE_START)
+            }
+            MemLayout::DISPLAY_START..=MemLayout::DISPLAY_END => {
+                self.display.read_byte(addr - MemLayout::DISPLAY_START)
+            }
+            MemLayout::VRAM_WINDOW_START..=MemLayout::VRAM_WINDOW_END => {
+                self.display.read_vram(addr - MemLayout::VRAM_WINDOW_START)
+            }
+            MemLayout::SPRITES_START..=MemLayout::SPRITES_END => {
+                self.sprites.read_byte(addr - MemLayout::SPRITES_START)
+            }
+            MemLayout::SPRITE_COLLISIONS => self.collisions.0,
+            MemLayout::SPRITE_BG_COLLISIONS => self.collisions.1,
+            _ => self.operative_memory.read_byte(addr),
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, data: u8) {
+        match addr {
+            MemLayout::EXIT_PORT => self.exit_code = Some(data),
+            MemLayout::RNG_PORT => self.rng.write_byte(addr, data),
+            MemLayout::RTC_START..=MemLayout::RTC_END => {
+                self.rtc.write_byte(addr - MemLayout::RTC_START, data)
+            }
+            MemLayout::AUDIO_START..=MemLayout::AUDIO_END => {
+                self.apu.write_byte(addr - MemLayout::AUDIO_START, data)
+            }
+            MemLayout::SPEAKER => self.apu.toggle_speaker(self.cycles),
+            MemLayout::PALETTE_START..=MemLayout::PALETTE_END => {
+                self.palette
+                    .write_byte(addr - MemLayout::PALETTE_START, data);
+                self.display.mark_dirty();
+            }
+            MemLayout::DISPLAY_START..=MemLayout::DISPLAY_END => self
+                .display
+                .write_byte(addr - MemLayout::DISPLAY_START, data),
+            MemLayout::VRAM_WINDOW_START..=MemLayout::VRAM_WINDOW_END => self
+                .display
+                .write_vram(addr - MemLayout::VRAM_WINDOW_START, data),
+            MemLayout::SPRITES_START..=MemLayout::SPRITES_END => self
+                .sprites
+                .write_byte(addr - MemLayout::SPRITES_START, data),
+            MemLayout::SPRITE_COLLISIONS | MemLayout::SPRITE_BG_COLLISIONS => {}
+            MemLayout::VGA_BUF_START..=MemLayout::VGA_BUF1_END => {
+                self.operative_memory.write_byte(addr, data);
+
+                let offset = (addr - MemLayout::VGA_BUF_START) as usize;
+                self.vga_writes[offset / u64::BITS as usize] |= 1 << (offset % u64::BITS as usize);
+            }
+            MemLayout::TEXT_BUF_START..=MemLayout::TEXT_ATTR_END => {
+                self.operative_memory.write_byte(addr, data);
+                self.display.mark_dirty();
+            }
+            _ => self.operative_memory.write_byte(addr, data),
+        }
     }
 }
@@ -0,0 +1,267 @@
+use std::cell::Cell;
+use std::mem;
+
+use super::StorageDevice;
+use crate::cpu::CLOCK_HZ;
+
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// 1/8, 1/4, 1/2 and 3/4 of the period is spent high
+const DUTY_CYCLES: [f32; 4] = [0.125, 0.25, 0.5, 0.75];
+const MAX_VOLUME: u8 = 0x0F;
+
+#[derive(Default)]
+struct Square {
+    freq: u16,
+    duty: u8,
+    volume: u8,
+    phase: f32,
+}
+
+impl Square {
+    fn sample(&mut self) -> f32 {
+        if self.freq == 0 || self.volume == 0 {
+            return 0.0;
+        }
+
+        self.phase = (self.phase + self.freq as f32 / SAMPLE_RATE as f32).fract();
+        let level = self.volume as f32 / MAX_VOLUME as f32;
+        if self.phase < DUTY_CYCLES[(self.duty & 0b11) as usize] {
+            level
+        } else {
+            -level
+        }
+    }
+}
+
+struct Noise {
+    freq: u16,
+    short: bool,
+    volume: u8,
+    phase: f32,
+    lfsr: u16,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            freq: 0,
+            short: false,
+            volume: 0,
+            phase: 0.0,
+            lfsr: 1,
+        }
+    }
+}
+
+impl Noise {
+    fn sample(&mut self) -> f32 {
+        if self.freq == 0 || self.volume == 0 {
+            return 0.0;
+        }
+
+        self.phase += self.freq as f32 / SAMPLE_RATE as f32;
+        while self.phase >= 1.0 {
+            self.phase -= 1.0;
+
+            // 15-bit LFSR, tapping bit 6 instead of bit 1 gives the short, metallic loop
+            let tap = if self.short { 6 } else { 1 };
+            let feedback = (self.lfsr ^ (self.lfsr >> tap)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        }
+
+        let level = self.volume as f32 / MAX_VOLUME as f32;
+        if self.lfsr & 1 == 0 {
+            level
+        } else {
+            -level
+        }
+    }
+}
+
+// A 1-bit speaker cone, flipped by accesses at known cycle timestamps.
+// Each sample is the fraction of its window the cone spent out,
+// passed through a DC blocker so an idle cone is silent.
+#[derive(Default)]
+struct Speaker {
+    out: Cell<bool>,
+    last_toggle: Cell<u64>,
+    out_cycles: Cell<u64>,
+    window_start: u64,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Speaker {
+    fn settle(&self, now: u64) {
+        if self.out.get() {
+            let elapsed = now.saturating_sub(self.last_toggle.get());
+            self.out_cycles.set(self.out_cycles.get() + elapsed);
+        }
+
+        self.last_toggle.set(now);
+    }
+
+    fn toggle(&self, now: u64) {
+        self.settle(now);
+        self.out.set(!self.out.get());
+    }
+
+    fn sample(&mut self, now: u64) -> f32 {
+        self.settle(now);
+
+        let window = now.saturating_sub(self.window_start);
+        let level = if window > 0 {
+            self.out_cycles.get() as f32 / window as f32
+        } else {
+            self.out.get() as u8 as f32
+        };
+
+        self.out_cycles.set(0);
+        self.window_start = now;
+
+        let filtered = level - self.prev_in + 0.995 * self.prev_out;
+        self.prev_in = level;
+        self.prev_out = filtered;
+        filtered
+    }
+}
+
+pub struct Apu {
+    enabled: bool,
+    // Speed of the emulated CPU, which the samples keep time with
+    clock_hz: u64,
+    squares: [Square; 2],
+    noise: Noise,
+    speaker: Speaker,
+    cycle_acc: u64,
+    samples: Vec<i16>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu {
+            enabled: false,
+            clock_hz: CLOCK_HZ,
+            squares: Default::default(),
+            noise: Noise::default(),
+            speaker: Speaker::default(),
+            cycle_acc: 0,
+            samples: vec![],
+        }
+    }
+}
+
+impl Apu {
+    // Register offsets from MemLayout::AUDIO_START
+    pub const SQ1_FREQ_LO: u16 = 0x0;
+    pub const SQ1_FREQ_HI: u16 = 0x1;
+    pub const SQ1_DUTY: u16 = 0x2;
+    pub const SQ1_VOLUME: u16 = 0x3;
+    pub const SQ2_FREQ_LO: u16 = 0x4;
+    pub const SQ2_FREQ_HI: u16 = 0x5;
+    pub const SQ2_DUTY: u16 = 0x6;
+    pub const SQ2_VOLUME: u16 = 0x7;
+    pub const NOISE_FREQ_LO: u16 = 0x8;
+    pub const NOISE_FREQ_HI: u16 = 0x9;
+    pub const NOISE_MODE: u16 = 0xA;
+    pub const NOISE_VOLUME: u16 = 0xB;
+
+    // Samples are only produced while some output consumes them
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    // Samples cover a second of emulated time per SAMPLE_RATE samples at `hz`, so they play in
+    // real time while the CPU is paced at that speed
+    pub fn set_clock(&mut self, hz: u64) {
+        self.clock_hz = hz;
+    }
+
+    pub fn reset(&mut self) {
+        *self = Apu {
+            enabled: self.enabled,
+            clock_hz: self.clock_hz,
+            ..Apu::default()
+        };
+    }
+
+    pub fn pending_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        mem::take(&mut self.samples)
+    }
+
+    pub fn toggle_speaker(&self, now: u64) {
+        self.speaker.toggle(now);
+    }
+
+    // `now` is the cycle count at the end of the ticked cycles
+    pub fn tick(&mut self, cycles: u8, now: u64) {
+        if !self.enabled {
+            return;
+        }
+
+        self.cycle_acc += cycles as u64 * SAMPLE_RATE as u64;
+        while self.cycle_acc >= self.clock_hz {
+            self.cycle_acc -= self.clock_hz;
+
+            let boundary = now - self.cycle_acc / SAMPLE_RATE as u64;
+            let mix = (self.squares[0].sample()
+                + self.squares[1].sample()
+                + self.noise.sample()
+                + self.speaker.sample(boundary))
+                / 4.0;
+
+            self.samples.push((mix * i16::MAX as f32 / 2.0) as i16);
+        }
+    }
+}
+
+impl StorageDevice for Apu {
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            Apu::SQ1_FREQ_LO => self.squares[0].freq.to_le_bytes()[0],
+            Apu::SQ1_FREQ_HI => self.squares[0].freq.to_le_bytes()[1],
+            Apu::SQ1_DUTY => self.squares[0].duty,
+            Apu::SQ1_VOLUME => self.squares[0].volume,
+            Apu::SQ2_FREQ_LO => self.squares[1].freq.to_le_bytes()[0],
+            Apu::SQ2_FREQ_HI => self.squares[1].freq.to_le_bytes()[1],
+            Apu::SQ2_DUTY => self.squares[1].duty,
+            Apu::SQ2_VOLUME => self.squares[1].volume,
+            Apu::NOISE_FREQ_LO => self.noise.freq.to_le_bytes()[0],
+            Apu::NOISE_FREQ_HI => self.noise.freq.to_le_bytes()[1],
+            Apu::NOISE_MODE => self.noise.short as u8,
+            Apu::NOISE_VOLUME => self.noise.volume,
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, data: u8) {
+        fn set_lo(word: &mut u16, data: u8) {
+            *word = (*word & 0xFF00) | data as u16;
+        }
+
+        fn set_hi(word: &mut u16, data: u8) {
+            *word = (*word & 0x00FF) | ((data as u16) << 8);
+        }
+
+        match addr {
+            Apu::SQ1_FREQ_LO => set_lo(&mut self.squares[0].freq, data),
+            Apu::SQ1_FREQ_HI => set_hi(&mut self.squares[0].freq, data),
+            Apu::SQ1_DUTY => self.squares[0].duty = data & 0b11,
+            Apu::SQ1_VOLUME => self.squares[0].volume = data.min(MAX_VOLUME),
+            Apu::SQ2_FREQ_LO => set_lo(&mut self.squares[1].freq, data),
+            Apu::SQ2_FREQ_HI => set_hi(&mut self.squares[1].freq, data),
+            Apu::SQ2_DUTY => self.squares[1].duty = data & 0b11,
+            Apu::SQ2_VOLUME => self.squares[1].volume = data.min(MAX_VOLUME),
+            Apu::NOISE_FREQ_LO => set_lo(&mut self.noise.freq, data),
+            Apu::NOISE_FREQ_HI => set_hi(&mut self.noise.freq, data),
+            Apu::NOISE_MODE => self.noise.short = data & 1 == 1,
+            Apu::NOISE_VOLUME => self.noise.volume = data.min(MAX_VOLUME),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use super::StorageDevice;
+use crate::cpu::{MemLayout, CLOCK_HZ};
+
+pub const VRAM_SIZE: usize = 0x8000;
+pub const VRAM_BANK_SIZE: usize = 0x1000;
+
+pub const FRAME_RATE: u64 = 60;
+pub const CYCLES_PER_FRAME: u64 = CLOCK_HZ / FRAME_RATE;
+// The last eighth of every frame is the vertical blank
+pub const VBLANK_START: u64 = CYCLES_PER_FRAME - CYCLES_PER_FRAME / 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GraphicsMode {
+    Vga,
+    Bitmap128x128,
+    Bitmap256x192,
+    Text40x25,
+}
+
+impl GraphicsMode {
+    pub fn resolution(&self) -> (u32, u32) {
+        match self {
+            GraphicsMode::Vga => (32, 32),
+            GraphicsMode::Bitmap128x128 => (128, 128),
+            GraphicsMode::Bitmap256x192 => (256, 192),
+            GraphicsMode::Text40x25 => (320, 200),
+        }
+    }
+}
+
+pub struct Display {
+    mode: u8,
+    bank: u8,
+    vram: Box<[u8]>,
+    dirty: bool,
+    vga_page: u8,
+    vblank_irq: u8,
+    frame_cycle: u64,
+    frame_ready: bool,
+    irq: bool,
+    nmi: bool,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display {
+            mode: 0,
+            bank: 0,
+            vram: vec![0; VRAM_SIZE].into_boxed_slice(),
+            dirty: true,
+            vga_page: 0,
+            vblank_irq: 0,
+            frame_cycle: 0,
+            frame_ready: false,
+            irq: false,
+            nmi: false,
+        }
+    }
+}
+
+impl Display {
+    // Register offsets from MemLayout::DISPLAY_START
+    pub const MODE: u16 = 0x0;
+    pub const VRAM_BANK: u16 = 0x1;
+    pub const STATUS: u16 = 0x2;
+    pub const VBLANK_IRQ: u16 = 0x3;
+    pub const VGA_PAGE: u16 = 0x4;
+
+    const STATUS_VBLANK: u8 = 0b1000_0000;
+
+    // Values of the VBLANK_IRQ register
+    const VBLANK_RAISES_IRQ: u8 = 1;
+    const VBLANK_RAISES_NMI: u8 = 2;
+
+    pub fn reset(&mut self) {
+        *self = Display::default();
+    }
+
+    pub fn mode(&self) -> GraphicsMode {
+        match self.mode {
+            1 => GraphicsMode::Bitmap128x128,
+            2 => GraphicsMode::Bitmap256x192,
+            3 => GraphicsMode::Text40x25,
+            _ => GraphicsMode::Vga,
+        }
+    }
+
+    // First address of the VGA Buffer page being shown
+    pub fn vga_page_start(&self) -> u16 {
+        match self.vga_page {
+            0 => MemLayout::VGA_BUF_START,
+            _ => MemLayout::VGA_BUF1_START,
+        }
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    // Whether VRAM, text or anything affecting their colors changed since the last call
+    pub fn take_dirty(&mut self) -> bool {
+        let dirty = self.dirty;
+        self.dirty = false;
+        dirty
+    }
+
+    pub fn in_vblank(&self) -> bool {
+        self.frame_cycle >= VBLANK_START
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        let was_in_vblank = self.in_vblank();
+        self.frame_cycle += cycles as u64;
+
+        if self.frame_cycle >= CYCLES_PER_FRAME {
+            self.frame_cycle -= CYCLES_PER_FRAME;
+            self.irq = false;
+        } else if !was_in_vblank && self.in_vblank() {
+            self.frame_ready = true;
+            match self.vblank_irq {
+                Display::VBLANK_RAISES_IRQ => self.irq = true,
+                Display::VBLANK_RAISES_NMI => self.nmi = true,
+                _ => {}
+            }
+        }
+    }
+
+    // Whether a vertical blank started since the last call, i.e. a complete frame can be shown
+    pub fn take_frame_ready(&mut self) -> bool {
+        let ready = self.frame_ready;
+        self.frame_ready = false;
+        ready
+    }
+
+    // An IRQ stays pending until it is serviced or the vertical blank ends
+    pub fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    pub fn ack_irq(&mut self) {
+        self.irq = false;
+    }
+
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi;
+        self.nmi = false;
+        nmi
+    }
+
+    fn vram_offset(&self, window_addr: u16) -> usize {
+        (self.bank as usize * VRAM_BANK_SIZE + window_addr as usize) % VRAM_SIZE
+    }
+
+    pub fn read_vram(&self, window_addr: u16) -> u8 {
+        self.vram[self.vram_offset(window_addr)]
+    }
+
+    pub fn write_vram(&mut self, window_addr: u16, data: u8) {
+        let offset = self.vram_offset(window_addr);
+        self.vram[offset] = data;
+        self.dirty = true;
+    }
+}
+
+impl StorageDevice for Display {
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            Display::MODE => self.mode,
+            Display::VRAM_BANK => self.bank,
+            Display::STATUS if self.in_vblank() => Display::STATUS_VBLANK,
+            Display::VBLANK_IRQ => self.vblank_irq,
+            Display::VGA_PAGE => self.vga_page,
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, data: u8) {
+        match addr {
+            Display::MODE => {
+                self.mode = data;
+                self.dirty = true;
+            }
+            Display::VRAM_BANK => self.bank = data % (VRAM_SIZE / VRAM_BANK_SIZE) as u8,
+            Display::VBLANK_IRQ => self.vblank_irq = data,
+            Display::VGA_PAGE => {
+                self.vga_page = data & 1;
+                self.dirty = true;
+            }
+            _ => {}
+        }
+    }
+}
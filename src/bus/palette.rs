@@ -0,0 +1,57 @@
+use super::StorageDevice;
+
+pub const PALETTE_SIZE: usize = 16;
+
+// The colors of the README color chart
+const DEFAULT_PALETTE: [[u8; 3]; PALETTE_SIZE] = [
+    [0x00, 0x00, 0x00], // 0: black
+    [0xFF, 0xFF, 0xFF], // 1: white
+    [0x88, 0x00, 0x00], // 2: red
+    [0xAA, 0xFF, 0xEE], // 3: cyan
+    [0xCC, 0x44, 0xCC], // 4: purple
+    [0x00, 0xCC, 0x55], // 5: green
+    [0x00, 0x00, 0xAA], // 6: blue
+    [0xEE, 0xEE, 0x77], // 7: yellow
+    [0xDD, 0x88, 0x55], // 8: orange
+    [0x66, 0x44, 0x00], // 9: brown
+    [0xFF, 0x77, 0x77], // 10: light red
+    [0x33, 0x33, 0x33], // 11: dark grey
+    [0x77, 0x77, 0x77], // 12: grey
+    [0xAA, 0xFF, 0x66], // 13: light green
+    [0x00, 0x88, 0xFF], // 14: light blue
+    [0xBB, 0xBB, 0xBB], // 15: light grey
+];
+
+pub struct Palette {
+    colors: [[u8; 3]; PALETTE_SIZE],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            colors: DEFAULT_PALETTE,
+        }
+    }
+}
+
+impl Palette {
+    pub fn reset(&mut self) {
+        self.colors = DEFAULT_PALETTE;
+    }
+
+    // Only the low nibble selects a color
+    pub fn rgb(&self, color_idx: u8) -> [u8; 3] {
+        self.colors[(color_idx & 0x0F) as usize]
+    }
+}
+
+// Entry `n` occupies offsets `3n` (red), `3n + 1` (green) and `3n + 2` (blue)
+impl StorageDevice for Palette {
+    fn read_byte(&self, addr: u16) -> u8 {
+        self.colors[addr as usize / 3][addr as usize % 3]
+    }
+
+    fn write_byte(&mut self, addr: u16, data: u8) {
+        self.colors[addr as usize / 3][addr as usize % 3] = data;
+    }
+}
@@ -0,0 +1,66 @@
+use std::cell::Cell;
+
+use super::StorageDevice;
+
+#[derive(Clone, Copy)]
+pub enum RngMode {
+    Seeded(u64),
+    Entropy,
+}
+
+pub struct Rng {
+    mode: RngMode,
+    state: Cell<u64>,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(RngMode::Seeded(rand::random()))
+    }
+}
+
+impl Rng {
+    pub fn new(mode: RngMode) -> Self {
+        let mut rng = Rng {
+            mode,
+            state: Cell::new(0),
+        };
+
+        rng.reset();
+        rng
+    }
+
+    pub fn set_mode(&mut self, mode: RngMode) {
+        self.mode = mode;
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        if let RngMode::Seeded(seed) = self.mode {
+            self.state.set(seed);
+        }
+    }
+
+    // SplitMix64: any seed (including 0) gives a full-period sequence,
+    // and the output doesn't depend on the version of an external crate.
+    fn next(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        self.state.set(state);
+
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl StorageDevice for Rng {
+    fn read_byte(&self, _addr: u16) -> u8 {
+        match self.mode {
+            RngMode::Seeded(_) => (self.next() >> 56) as u8,
+            RngMode::Entropy => rand::random(),
+        }
+    }
+
+    fn write_byte(&mut self, _addr: u16, _data: u8) {}
+}
@@ -0,0 +1,108 @@
+use std::cell::Cell;
+
+use chrono::{Datelike, Local, NaiveDateTime, Timelike};
+
+use super::StorageDevice;
+
+#[derive(Clone, Copy)]
+pub enum RtcSource {
+    Host,
+    Frozen(NaiveDateTime),
+}
+
+impl RtcSource {
+    fn now(&self) -> NaiveDateTime {
+        match self {
+            RtcSource::Host => Local::now().naive_local(),
+            RtcSource::Frozen(time) => *time,
+        }
+    }
+}
+
+pub struct Rtc {
+    source: RtcSource,
+    bcd: bool,
+    latched: Cell<NaiveDateTime>,
+}
+
+impl Default for Rtc {
+    fn default() -> Self {
+        Self::new(RtcSource::Host)
+    }
+}
+
+impl Rtc {
+    // Register offsets from MemLayout::RTC_START
+    pub const CONTROL: u16 = 0x0;
+    pub const SECONDS: u16 = 0x1;
+    pub const MINUTES: u16 = 0x2;
+    pub const HOURS: u16 = 0x3;
+    pub const DAY: u16 = 0x4;
+    pub const MONTH: u16 = 0x5;
+    pub const YEAR: u16 = 0x6;
+    pub const CENTURY: u16 = 0x7;
+
+    const CONTROL_BCD: u8 = 0b0000_0001;
+
+    pub fn new(source: RtcSource) -> Self {
+        Rtc {
+            source,
+            bcd: false,
+            latched: Cell::new(source.now()),
+        }
+    }
+
+    pub fn set_source(&mut self, source: RtcSource) {
+        self.source = source;
+        self.latch();
+    }
+
+    pub fn reset(&mut self) {
+        self.bcd = false;
+        self.latch();
+    }
+
+    fn latch(&self) {
+        self.latched.set(self.source.now());
+    }
+
+    fn encode(&self, value: u32) -> u8 {
+        if self.bcd {
+            (((value / 10) << 4) | (value % 10)) as u8
+        } else {
+            value as u8
+        }
+    }
+
+    // Reads a register without latching the time
+    pub fn peek(&self, addr: u16) -> u8 {
+        let time = self.latched.get();
+        match addr {
+            Rtc::CONTROL if self.bcd => Rtc::CONTROL_BCD,
+            Rtc::SECONDS => self.encode(time.second()),
+            Rtc::MINUTES => self.encode(time.minute()),
+            Rtc::HOURS => self.encode(time.hour()),
+            Rtc::DAY => self.encode(time.day()),
+            Rtc::MONTH => self.encode(time.month()),
+            Rtc::YEAR => self.encode(time.year() as u32 % 100),
+            Rtc::CENTURY => self.encode(time.year() as u32 / 100),
+            _ => 0,
+        }
+    }
+}
+
+impl StorageDevice for Rtc {
+    fn read_byte(&self, addr: u16) -> u8 {
+        if addr == Rtc::SECONDS {
+            self.latch();
+        }
+
+        self.peek(addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, data: u8) {
+        if addr == Rtc::CONTROL {
+            self.bcd = data & Rtc::CONTROL_BCD == Rtc::CONTROL_BCD;
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use super::{OperativeMemory, StorageDevice};
+
+pub const SPRITE_COUNT: usize = 8;
+pub const SPRITE_SIZE: u32 = 8;
+const ENTRY_SIZE: usize = 8;
+
+// One row of 8 pixels per pattern byte, the leftmost pixel in bit 7
+pub struct Sprite {
+    pub x: u8,
+    pub y: u8,
+    pub pattern: u16,
+    pub color: u8,
+    pub enabled: bool,
+}
+
+impl Sprite {
+    // Entry layout, as offsets from the start of the sprite's entry
+    pub const X: u16 = 0x0;
+    pub const Y: u16 = 0x1;
+    pub const PATTERN_LO: u16 = 0x2;
+    pub const PATTERN_HI: u16 = 0x3;
+    pub const COLOR: u16 = 0x4;
+    pub const FLAGS: u16 = 0x5;
+
+    pub const FLAGS_ENABLE: u8 = 0b0000_0001;
+
+    fn covers(&self, x: u32, y: u32, ram: &OperativeMemory) -> bool {
+        let (dx, dy) = (x.wrapping_sub(self.x as u32), y.wrapping_sub(self.y as u32));
+        if !self.enabled || dx >= SPRITE_SIZE || dy >= SPRITE_SIZE {
+            return false;
+        }
+
+        let row = ram.read_byte(self.pattern.wrapping_add(dy as u16));
+        row & (0x80 >> dx) != 0
+    }
+}
+
+pub struct Sprites {
+    table: [u8; SPRITE_COUNT * ENTRY_SIZE],
+}
+
+impl Default for Sprites {
+    fn default() -> Self {
+        Sprites {
+            table: [0; SPRITE_COUNT * ENTRY_SIZE],
+        }
+    }
+}
+
+impl Sprites {
+    pub fn reset(&mut self) {
+        *self = Sprites::default();
+    }
+
+    pub fn sprite(&self, idx: usize) -> Sprite {
+        let entry = &self.table[idx * ENTRY_SIZE..(idx + 1) * ENTRY_SIZE];
+        Sprite {
+            x: entry[Sprite::X as usize],
+            y: entry[Sprite::Y as usize],
+            pattern: u16::from_le_bytes([
+                entry[Sprite::PATTERN_LO as usize],
+                entry[Sprite::PATTERN_HI as usize],
+            ]),
+            color: entry[Sprite::COLOR as usize],
+            enabled: entry[Sprite::FLAGS as usize] & Sprite::FLAGS_ENABLE != 0,
+        }
+    }
+
+    // Bit n is set if sprite n has a pixel at (x, y)
+    pub(super) fn coverage(&self, x: u32, y: u32, ram: &OperativeMemory) -> u8 {
+        (0..SPRITE_COUNT)
+            .filter(|&idx| self.sprite(idx).covers(x, y, ram))
+            .fold(0, |mask, idx| mask | (1 << idx))
+    }
+}
+
+impl StorageDevice for Sprites {
+    fn read_byte(&self, addr: u16) -> u8 {
+        self.table.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    fn write_byte(&mut self, addr: u16, data: u8) {
+        if let Some(byte) = self.table.get_mut(addr as usize) {
+            *byte = data;
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::bus::Bus;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FrameKind {
+    Subroutine,
+    Break,
+    Irq,
+    Nmi,
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FrameKind::Subroutine => "JSR",
+            FrameKind::Break => "BRK",
+            FrameKind::Irq => "IRQ",
+            FrameKind::Nmi => "NMI",
+        };
+
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CallFrame {
+    pub kind: FrameKind,
+    // Address of the JSR or BRK instruction, or of the instruction an interrupt came before
+    pub call_site: u16,
+    pub entry: u16,
+    pub return_addr: u16,
+    // SP right after the call pushed its return address
+    pub sp: u16,
+}
+
+// A level of a backtrace: the code running at `addr`, and the call it made into the next
+// inner level, if any
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Level {
+    pub addr: u16,
+    pub call: Option<CallFrame>,
+}
+
+// Shadow stack of the calls and interrupts in progress, following JSR, BRK, IRQ and NMI
+// entries and their RTS and RTI returns
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+    warnings: Vec<String>,
+    // Instructions already warned about, so that loops don't repeat their warnings
+    warned: HashSet<u16>,
+}
+
+const OPCODE_BRK: u8 = 0x00;
+const OPCODE_JSR: u8 = 0x20;
+const OPCODE_RTI: u8 = 0x40;
+const OPCODE_RTS: u8 = 0x60;
+const OPCODE_TXS: u8 = 0x9A;
+
+impl CallStack {
+    pub fn reset(&mut self) {
+        *self = CallStack::default();
+    }
+
+    // Calls in progress, the outermost first
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    // The innermost level first, starting at `pc`
+    pub fn backtrace(&self, pc: u16) -> Vec<Level> {
+        let mut levels = vec![Level {
+            addr: pc,
+            call: None,
+        }];
+        levels.extend(self.frames.iter().rev().map(|frame| Level {
+            addr: frame.call_site,
+            call: Some(*frame),
+        }));
+
+        levels
+    }
+
+    // Warnings about code that manipulated the stack by hand since the last call
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn warn(&mut self, pc: u16, message: String) {
+        if self.warned.insert(pc) {
+            self.warnings.push(message);
+        }
+    }
+
+    // Records a call which just pushed its return address, ending at `sp`
+    pub(crate) fn enter(
+        &mut self,
+        kind: FrameKind,
+        call_site: u16,
+        entry: u16,
+        sp: u16,
+        bus: &Bus,
+    ) {
+        // Interrupts push the status register after the return address
+        let offset = if kind == FrameKind::Subroutine { 1 } else { 2 };
+        let return_addr = u16::from_le_bytes([
+            bus.peek(sp.wrapping_add(offset)),
+            bus.peek(sp.wrapping_add(offset + 1)),
+        ]);
+
+        self.frames.push(CallFrame {
+            kind,
+            call_site,
+            entry,
+            return_addr,
+            sp,
+        });
+    }
+
+    // Drops the calls whose return addresses are no longer on the stack, the innermost first
+    fn unwind(&mut self, sp: u16) -> Vec<CallFrame> {
+        let live = self
+            .frames
+            .iter()
+            .take_while(|frame| frame.sp >= sp)
+            .count();
+        self.frames.drain(live..).rev().collect()
+    }
+
+    // Follows the instruction `mnemonic` which ran at `pc`, moving SP from `sp_before` to `sp`
+    // and continuing at `next_pc`
+    pub(crate) fn track(
+        &mut self,
+        mnemonic: &str,
+        opcode: u8,
+        pc: u16,
+        (sp_before, sp): (u16, u16),
+        next_pc: u16,
+        bus: &Bus,
+    ) {
+        match opcode {
+            OPCODE_JSR => self.enter(FrameKind::Subroutine, pc, next_pc, sp, bus),
+            // A BRK without a handler, or a host syscall, doesn't push anything
+            OPCODE_BRK if sp.wrapping_add(3) == sp_before => {
+                self.enter(FrameKind::Break, pc, next_pc, sp, bus)
+            }
+            OPCODE_RTS | OPCODE_RTI => {
+                let expected = self.frames.last().copied();
+                let unwound = self.unwind(sp);
+                let matches = |frame: &CallFrame| {
+                    frame.sp == sp_before
+                        && (frame.kind == FrameKind::Subroutine) == (opcode == OPCODE_RTS)
+                };
+
+                match expected {
+                    Some(frame) if matches(&frame) && unwound.len() == 1 => {
+                        if frame.return_addr != next_pc {
+                            self.warn(
+                                pc,
+                                format!(
+                                    "{} at ${:04X} returns to ${:04X} instead of ${:04X}, where the {} at ${:04X} would return",
+                                    mnemonic, pc, next_pc, frame.return_addr, frame.kind, frame.call_site
+                                ),
+                            );
+                        }
+                    }
+                    Some(frame) => self.warn(
+                        pc,
+                        format!(
+                            "{} at ${:04X} doesn't match the {} at ${:04X}, the stack was changed by hand",
+                            mnemonic, pc, frame.kind, frame.call_site
+                        ),
+                    ),
+                    None => self.warn(
+                        pc,
+                        format!(
+                            "{} at ${:04X} returns to ${:04X} without a matching call",
+                            mnemonic, pc, next_pc
+                        ),
+                    ),
+                }
+            }
+            _ => {
+                let unwound = self.unwind(sp);
+                if let Some(frame) = unwound.first() {
+                    self.warn(
+                        pc,
+                        format!(
+                            "{} at ${:04X} removes the return address of the {} at ${:04X} from the stack",
+                            mnemonic, pc, frame.kind, frame.call_site
+                        ),
+                    );
+                } else if let (OPCODE_TXS, Some(frame)) = (opcode, self.frames.last()) {
+                    let message = format!(
+                        "TXS at ${:04X} moves the stack pointer inside the {} at ${:04X}",
+                        pc, frame.kind, frame.call_site
+                    );
+                    self.warn(pc, message);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::bus::display::FRAME_RATE;
+use crate::screen::Frame;
+
+// GIF delays are counted in hundredths of a second, and most viewers slow down anything below 2
+const MIN_GIF_DELAY: u32 = 2;
+
+// Nearest-neighbour upscale of the RGB24 frame
+fn scaled(frame: &Frame, scale: u32) -> (u32, u32, Vec<u8>) {
+    let (width, height) = (frame.width() * scale, frame.height() * scale);
+    let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let i = ((y / scale) * frame.width() + x / scale) as usize * 3;
+            pixels.extend_from_slice(&frame.pixels()[i..i + 3]);
+        }
+    }
+
+    (width, height, pixels)
+}
+
+pub fn save_png(frame: &Frame, scale: u32, path: &Path) -> Result<(), Box<dyn Error>> {
+    let (width, height, pixels) = scaled(frame, scale);
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(())
+}
+
+// First `<prefix>-<n>.<ext>` in the current directory that doesn't exist yet
+pub fn unused_path(prefix: &str, ext: &str) -> PathBuf {
+    (1..)
+        .map(|n| PathBuf::from(format!("{}-{}.{}", prefix, n, ext)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// The screen uses at most 16 colors, so frames are stored with their own palettes
+fn gif_frame(width: u32, height: u32, rgb: &[u8]) -> gif::Frame<'static> {
+    let mut palette: Vec<[u8; 3]> = vec![];
+    let indices = rgb
+        .chunks(3)
+        .map(
+            |pixel| match palette.iter().position(|color| color == pixel) {
+                Some(idx) => idx as u8,
+                None => {
+                    palette.push([pixel[0], pixel[1], pixel[2]]);
+                    (palette.len() - 1) as u8
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+
+    gif::Frame::from_palette_pixels(width as u16, height as u16, indices, palette.concat(), None)
+}
+
+enum Output {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        size: (u32, u32),
+        // A frame is only written once the next change shows how long it stayed on screen
+        pending: Option<gif::Frame<'static>>,
+        pending_frames: u32,
+    },
+    Frames {
+        dir: PathBuf,
+        count: u32,
+    },
+}
+
+fn flush_gif(
+    encoder: &mut gif::Encoder<BufWriter<File>>,
+    pending: &mut Option<gif::Frame<'static>>,
+    pending_frames: u32,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(mut frame) = pending.take() {
+        let delay = (pending_frames * 100 / FRAME_RATE as u32).max(MIN_GIF_DELAY);
+        frame.delay = delay.min(u16::MAX as u32) as u16;
+        encoder.write_frame(&frame)?;
+    }
+
+    Ok(())
+}
+
+// Records every displayed frame into an animated GIF, or into a directory of numbered PNGs
+pub struct Recorder {
+    path: PathBuf,
+    scale: u32,
+    output: Option<Output>,
+}
+
+impl Recorder {
+    // The output is only opened with the first frame, which also fixes the size of a GIF
+    pub fn new(path: &Path, scale: u32) -> Self {
+        Recorder {
+            path: path.to_path_buf(),
+            scale,
+            output: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn open(&self, frame: &Frame) -> Result<Output, Box<dyn Error>> {
+        match self.path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("gif") => {
+                let size = (frame.width() * self.scale, frame.height() * self.scale);
+                let file = BufWriter::new(File::create(&self.path)?);
+                let mut encoder = gif::Encoder::new(file, size.0 as u16, size.1 as u16, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+
+                Ok(Output::Gif {
+                    encoder,
+                    size,
+                    pending: None,
+                    pending_frames: 0,
+                })
+            }
+            _ => {
+                fs::create_dir_all(&self.path)?;
+                Ok(Output::Frames {
+                    dir: self.path.clone(),
+                    count: 0,
+                })
+            }
+        }
+    }
+
+    // Called once per displayed frame, `changed` tells whether it differs from the previous one.
+    // GIFs leave out frames in a resolution other than the first one.
+    pub fn record(&mut self, frame: &Frame, changed: bool) -> Result<(), Box<dyn Error>> {
+        if self.output.is_none() {
+            self.output = Some(self.open(frame)?);
+        }
+
+        match self.output.as_mut().unwrap() {
+            Output::Gif {
+                encoder,
+                size,
+                pending,
+                pending_frames,
+            } => {
+                if changed || pending.is_none() {
+                    let (width, height, pixels) = scaled(frame, self.scale);
+                    if (width, height) == *size {
+                        flush_gif(encoder, pending, *pending_frames)?;
+                        *pending = Some(gif_frame(width, height, &pixels));
+                        *pending_frames = 1;
+                        return Ok(());
+                    }
+                }
+
+                *pending_frames += 1;
+            }
+            Output::Frames { dir, count } => {
+                *count += 1;
+                save_png(
+                    frame,
+                    self.scale,
+                    &dir.join(format!("frame-{:06}.png", count)),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(Output::Gif {
+            encoder,
+            pending,
+            pending_frames,
+            ..
+        }) = &mut self.output
+        {
+            flush_gif(encoder, pending, *pending_frames)?;
+        }
+
+        Ok(())
+    }
+}
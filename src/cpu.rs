@@ -1,10 +1,13 @@
+use std::path::PathBuf;
 use std::{fmt, mem};
 
 use derive_getters::Getters;
 
 use crate::bus::*;
+use crate::call_stack::{CallStack, FrameKind};
 use crate::opcodes::*;
 use crate::status_register::*;
+use crate::syscall::{HostServices, SYSCALL_SIGNATURE};
 
 #[derive(Getters)]
 pub struct CPU {
@@ -17,8 +20,16 @@ pub struct CPU {
 
     #[getter(skip)]
     bus: Bus,
+    #[getter(skip)]
+    host: HostServices,
+    #[getter(skip)]
+    extra_cycles: u8,
+    call_stack: CallStack,
 }
 
+// Nominal clock rate, in cycles per second of emulated time
+pub const CLOCK_HZ: u64 = 1_000_000;
+
 pub struct MemLayout;
 impl MemLayout {
     pub const KEYCODE_ADDR: u16 = 0xFF;
@@ -26,8 +37,36 @@ impl MemLayout {
     pub const STACK_END: u16 = 0x1FF;
     pub const VGA_BUF_START: u16 = 0x200;
     pub const VGA_BUF_END: u16 = 0x5FF;
+    pub const VGA_BUF1_START: u16 = 0x600;
+    pub const VGA_BUF1_END: u16 = 0x9FF;
+    pub const VRAM_WINDOW_START: u16 = 0x6000;
+    pub const VRAM_WINDOW_END: u16 = 0x6FFF;
+    pub const TEXT_BUF_START: u16 = 0x7000;
+    pub const TEXT_BUF_END: u16 = 0x73E7;
+    pub const TEXT_ATTR_START: u16 = 0x7400;
+    pub const TEXT_ATTR_END: u16 = 0x77E7;
+    pub const ARGS_START: u16 = 0x7E00;
+    pub const ARGS_END: u16 = 0x7EFF;
+    pub const IO_START: u16 = 0x7F00;
+    pub const EXIT_PORT: u16 = 0x7F00;
+    pub const RNG_PORT: u16 = 0x7F01;
+    pub const RTC_START: u16 = 0x7F08;
+    pub const RTC_END: u16 = 0x7F0F;
+    pub const AUDIO_START: u16 = 0x7F10;
+    pub const AUDIO_END: u16 = 0x7F1F;
+    pub const SPEAKER: u16 = 0x7F30;
+    pub const PALETTE_START: u16 = 0x7F40;
+    pub const PALETTE_END: u16 = 0x7F6F;
+    pub const DISPLAY_START: u16 = 0x7F80;
+    pub const DISPLAY_END: u16 = 0x7F8F;
+    pub const SPRITES_START: u16 = 0x7FA0;
+    pub const SPRITES_END: u16 = 0x7FDF;
+    pub const SPRITE_COLLISIONS: u16 = 0x7FE0;
+    pub const SPRITE_BG_COLLISIONS: u16 = 0x7FE1;
+    pub const IO_END: u16 = 0x7FFF;
     pub const CS_START: u16 = 0x8000;
     pub const CS_END: u16 = 0xFFF0;
+    pub const NMI_VECTOR: u16 = 0xFFFA;
     pub const RESET_VECTOR: u16 = 0xFFFC;
     pub const IRQ_VECTOR: u16 = 0xFFFE;
 }
@@ -135,6 +174,9 @@ impl CPU {
             sp: MemLayout::STACK_END,
             p: StatusRegister::default(),
             bus: Bus::new(),
+            host: HostServices::new(),
+            extra_cycles: 0,
+            call_stack: CallStack::default(),
         }
     }
 
@@ -186,9 +228,34 @@ impl CPU {
         }
     }
 
+    // Reads the operand of an instruction which doesn't write it back. Indexing across a page
+    // boundary costs these instructions a cycle.
+    fn read_operand(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = self.get_operand_address(mode);
+        let index = match mode {
+            AddressingMode::Absolute_X => self.x,
+            AddressingMode::Absolute_Y | AddressingMode::Indirect_Y => self.y,
+            _ => 0,
+        };
+
+        if addr.wrapping_sub(index as u16) & 0xFF00 != addr & 0xFF00 {
+            self.extra_cycles += 1;
+        }
+
+        self.mem_read_byte(addr)
+    }
+
     pub fn jump_near_immediate(&mut self) {
         let offset = self.mem_read_byte(self.pc) as i8;
-        let address = self.pc.wrapping_add(1).wrapping_add(offset as u16);
+        let next = self.pc.wrapping_add(1);
+        let address = next.wrapping_add(offset as u16);
+
+        // A taken branch costs a cycle, crossing a page costs another one
+        self.extra_cycles += if next & 0xFF00 == address & 0xFF00 {
+            1
+        } else {
+            2
+        };
         self.pc = address;
     }
 
@@ -199,8 +266,126 @@ impl CPU {
         self.pc = MemLayout::CS_START;
         self.sp = MemLayout::STACK_END;
 
-        mem::drop(mem::take(&mut self.p));
-        mem::drop(mem::take(&mut self.bus));
+        self.p = StatusRegister::default();
+        self.bus.reset();
+        self.host.close_files();
+        self.call_stack.reset();
+    }
+
+    // Restarts the program through the reset vector, keeping memory intact
+    pub fn soft_reset(&mut self) {
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.pc = self.mem_read_word(MemLayout::RESET_VECTOR);
+        self.sp = MemLayout::STACK_END;
+
+        self.p = StatusRegister::default();
+        self.extra_cycles = 0;
+        self.bus.reset_devices();
+        self.host.close_files();
+        self.call_stack.reset();
+    }
+
+    pub fn set_a(&mut self, value: u8) {
+        self.a = value;
+    }
+
+    pub fn set_x(&mut self, value: u8) {
+        self.x = value;
+    }
+
+    pub fn set_y(&mut self, value: u8) {
+        self.y = value;
+    }
+
+    pub fn set_p(&mut self, value: u8) {
+        self.p = StatusRegister::new(value);
+    }
+
+    pub fn set_sp(&mut self, value: u16) {
+        self.sp = value;
+    }
+
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    pub fn set_sandbox(&mut self, dir: PathBuf) {
+        self.host.set_sandbox(dir);
+    }
+
+    fn syscall(&mut self) {
+        let mut host = mem::take(&mut self.host);
+        let block = u16::from_le_bytes([self.x, self.y]);
+        let result = host.call(self.a, block, self);
+        self.host = host;
+
+        match result {
+            Ok(value) => {
+                self.a = value;
+                self.p.unset_c();
+            }
+            Err(e) => {
+                self.a = e as u8;
+                self.p.set_c();
+            }
+        }
+    }
+
+    // Hardware interrupts are taken between instructions. An NMI can't be masked by the I flag.
+    fn service_interrupts(&mut self) {
+        if self.bus.display_mut().take_nmi() {
+            self.interrupt(MemLayout::NMI_VECTOR);
+        } else if self.bus.display().irq_pending() && !self.p.I() {
+            self.bus.display_mut().ack_irq();
+            self.interrupt(MemLayout::IRQ_VECTOR);
+        }
+    }
+
+    // Unlike BRK, a null vector ignores the interrupt
+    fn interrupt(&mut self, vector: u16) {
+        let handler_addr = self.mem_read_word(vector);
+        if handler_addr == 0 {
+            return;
+        }
+
+        self.stack_push_word(self.pc);
+        self.p.unset_b();
+        self.p.set_b2();
+        self.stack_push_byte(self.p.pack());
+        self.p.set_i();
+
+        let kind = if vector == MemLayout::NMI_VECTOR {
+            FrameKind::Nmi
+        } else {
+            FrameKind::Irq
+        };
+        self.call_stack
+            .enter(kind, self.pc, handler_addr, self.sp, &self.bus);
+
+        self.pc = handler_addr;
+        self.extra_cycles += 7;
+    }
+
+    pub fn call_stack_mut(&mut self) -> &mut CallStack {
+        &mut self.call_stack
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.bus.cycles()
+    }
+
+    pub fn exit_code(&self) -> Option<u8> {
+        self.bus.exit_code()
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
@@ -215,668 +400,727 @@ impl CPU {
         self.mem_write_word(MemLayout::RESET_VECTOR, MemLayout::CS_START);
     }
 
+    pub fn load_args(&mut self, args: &[String], env: &[String]) -> Result<(), String> {
+        // [argc] [envc] [argv..., 0x0000] [envp..., 0x0000] [strings...]
+        let table_len = 2 + (args.len() + 1 + env.len() + 1) * 2;
+        let strings_len = args.iter().chain(env).map(|s| s.len() + 1).sum::<usize>();
+        if args.len() > 0xFF
+            || env.len() > 0xFF
+            || table_len + strings_len > (MemLayout::ARGS_END - MemLayout::ARGS_START + 1) as usize
+        {
+            return Err(String::from(
+                "The program arguments and environment don't fit in memory",
+            ));
+        }
+
+        self.mem_write_byte(MemLayout::ARGS_START, args.len() as u8);
+        self.mem_write_byte(MemLayout::ARGS_START + 1, env.len() as u8);
+
+        let mut table_ptr = MemLayout::ARGS_START + 2;
+        let mut string_ptr = MemLayout::ARGS_START + table_len as u16;
+        for list in [args, env].iter() {
+            for string in list.iter() {
+                self.mem_write_word(table_ptr, string_ptr);
+                table_ptr += 2;
+
+                for byte in string.bytes().chain(Some(0)) {
+                    self.mem_write_byte(string_ptr, byte);
+                    string_ptr += 1;
+                }
+            }
+
+            self.mem_write_word(table_ptr, 0);
+            table_ptr += 2;
+        }
+
+        Ok(())
+    }
+
     pub fn run(&mut self) {
         self.run_with_callback(|_| {});
     }
 
+    // Calls back after every instruction, including the one that writes the exit port, but
+    // not after a BRK that halts the program
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
         F: FnMut(&mut CPU),
     {
-        let opcodes = &*OPCODES_MAP;
-
         loop {
-            let opcode = opcodes
-                .get(&self.mem_read_byte(self.pc))
-                .expect("SIGILL: Unknown Instruction");
-
-            self.pc += 1;
-            let pc_bak = self.pc;
-
-            match opcode.code {
-                // Arithmetic & logic
-                // ADC
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
-                    let carry = if *self.p.C() { 1u8 } else { 0u8 };
-
-                    if (self.a ^ value) & 0x80 == 0x80 {
-                        self.p.unset_v();
-                    } else {
-                        self.p.set_v();
-                    }
-
-                    let mut result: u16 = self.a as u16 + value as u16 + carry as u16;
-                    self.p.ensure_z(result as u8);
-
-                    if *self.p.D() {
-                        result = (self.a & 0x0f) as u16 + (value & 0x0f) as u16 + carry as u16;
-                        if result >= 0xa {
-                            result = 0x10 | (result + 0x6) & 0x0f;
-                        }
+            let running = self.step();
+            if running || self.exit_code().is_some() {
+                callback(self);
+            }
 
-                        result += (self.a & 0xf0) as u16 + (value & 0xf0) as u16;
-                        self.p.ensure_n(result as u8);
-
-                        if result >= 0xa0 {
-                            self.p.set_c();
-                            if result >= 0x180 {
-                                self.p.unset_v();
-                            }
-                            result += 0x60;
-                        } else {
-                            self.p.unset_c();
-                            if result < 0x80 {
-                                self.p.unset_v();
-                            }
-                        }
-                    } else {
-                        if result >= 0x100 {
-                            self.p.set_c();
-                            if result >= 0x180 {
-                                self.p.unset_v();
-                            }
-                        } else {
-                            self.p.unset_c();
-                            if result < 0x80 {
-                                self.p.unset_v();
-                            }
-                        }
+            if !running {
+                break;
+            }
+        }
+    }
 
-                        self.p.ensure_n(result as u8);
-                    }
+    // Executes a single instruction. Returns false once the program has halted.
+    pub fn step(&mut self) -> bool {
+        let opcodes = &*OPCODES_MAP;
 
-                    self.a = result as u8;
+        let opcode = opcodes
+            .get(&self.mem_read_byte(self.pc))
+            .expect("SIGILL: Unknown Instruction");
+
+        let (start_pc, start_sp) = (self.pc, self.sp);
+        self.pc += 1;
+        let pc_bak = self.pc;
+
+        match opcode.code {
+            // Arithmetic & logic
+            // ADC
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
+                let value = self.read_operand(&opcode.mode);
+                let carry = if *self.p.C() { 1u8 } else { 0u8 };
+
+                if (self.a ^ value) & 0x80 == 0x80 {
+                    self.p.unset_v();
+                } else {
+                    self.p.set_v();
                 }
 
-                // AND
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
+                let mut result: u16 = self.a as u16 + value as u16 + carry as u16;
+                self.p.ensure_z(result as u8);
 
-                    self.a &= value;
-                    self.p.ensure_z(self.a);
-                    self.p.ensure_n(self.a);
-                }
+                if *self.p.D() {
+                    result = (self.a & 0x0f) as u16 + (value & 0x0f) as u16 + carry as u16;
+                    if result >= 0xa {
+                        result = 0x10 | (result + 0x6) & 0x0f;
+                    }
 
-                // ASL
-                0x0a | 0x06 | 0x16 | 0x0e | 0x1e => {
-                    let mut value = match &opcode.mode {
-                        AddressingMode::NoneAddressing => self.a,
-                        _ => {
-                            let addr = self.get_operand_address(&opcode.mode);
-                            self.mem_read_byte(addr)
-                        }
-                    };
+                    result += (self.a & 0xf0) as u16 + (value & 0xf0) as u16;
+                    self.p.ensure_n(result as u8);
 
-                    if (value & 0b10000000) == 0b10000000 {
+                    if result >= 0xa0 {
                         self.p.set_c();
+                        if result >= 0x180 {
+                            self.p.unset_v();
+                        }
+                        result += 0x60;
                     } else {
                         self.p.unset_c();
+                        if result < 0x80 {
+                            self.p.unset_v();
+                        }
                     }
-
-                    value <<= 1;
-                    match &opcode.mode {
-                        AddressingMode::NoneAddressing => {
-                            self.a = value;
+                } else {
+                    if result >= 0x100 {
+                        self.p.set_c();
+                        if result >= 0x180 {
+                            self.p.unset_v();
                         }
-                        _ => {
-                            let addr = self.get_operand_address(&opcode.mode);
-                            self.mem_write_byte(addr, value);
+                    } else {
+                        self.p.unset_c();
+                        if result < 0x80 {
+                            self.p.unset_v();
                         }
                     }
 
-                    self.p.ensure_z(value);
-                    self.p.ensure_n(value);
+                    self.p.ensure_n(result as u8);
                 }
 
-                // BIT
-                0x24 | 0x2c => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
-                    let result = value & self.a;
+                self.a = result as u8;
+            }
 
-                    self.p.ensure_z(result);
-                    self.p.ensure_n(value);
+            // AND
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                let value = self.read_operand(&opcode.mode);
 
-                    if value & 0x40 == 0x40 {
-                        self.p.set_v();
-                    } else {
-                        self.p.unset_v();
+                self.a &= value;
+                self.p.ensure_z(self.a);
+                self.p.ensure_n(self.a);
+            }
+
+            // ASL
+            0x0a | 0x06 | 0x16 | 0x0e | 0x1e => {
+                let mut value = match &opcode.mode {
+                    AddressingMode::NoneAddressing => self.a,
+                    _ => {
+                        let addr = self.get_operand_address(&opcode.mode);
+                        self.mem_read_byte(addr)
                     }
-                }
+                };
 
-                // CMP
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
+                if (value & 0b10000000) == 0b10000000 {
+                    self.p.set_c();
+                } else {
+                    self.p.unset_c();
+                }
 
-                    if value <= self.a {
-                        self.p.set_c();
-                    } else {
-                        self.p.unset_c();
+                value <<= 1;
+                match &opcode.mode {
+                    AddressingMode::NoneAddressing => {
+                        self.a = value;
+                    }
+                    _ => {
+                        let addr = self.get_operand_address(&opcode.mode);
+                        self.mem_write_byte(addr, value);
                     }
-
-                    self.p.ensure_z(self.a.wrapping_sub(value));
-                    self.p.ensure_n(self.a.wrapping_sub(value));
                 }
 
-                // DEC
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
-                    let result = value.wrapping_sub(1);
+                self.p.ensure_z(value);
+                self.p.ensure_n(value);
+            }
+
+            // BIT
+            0x24 | 0x2c => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let value = self.mem_read_byte(addr);
+                let result = value & self.a;
 
-                    self.mem_write_byte(addr, result);
-                    self.p.ensure_z(result);
-                    self.p.ensure_n(result);
+                self.p.ensure_z(result);
+                self.p.ensure_n(value);
+
+                if value & 0x40 == 0x40 {
+                    self.p.set_v();
+                } else {
+                    self.p.unset_v();
                 }
+            }
 
-                // EOR
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
+            // CMP
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                let value = self.read_operand(&opcode.mode);
 
-                    self.a ^= value;
-                    self.p.ensure_z(self.a);
-                    self.p.ensure_n(self.a);
+                if value <= self.a {
+                    self.p.set_c();
+                } else {
+                    self.p.unset_c();
                 }
 
-                // LSR
-                0x4a | 0x46 | 0x56 | 0x4e | 0x5e => {
-                    let mut value = match &opcode.mode {
-                        AddressingMode::NoneAddressing => self.a,
-                        _ => {
-                            let addr = self.get_operand_address(&opcode.mode);
-                            self.mem_read_byte(addr)
-                        }
-                    };
+                self.p.ensure_z(self.a.wrapping_sub(value));
+                self.p.ensure_n(self.a.wrapping_sub(value));
+            }
 
-                    if (value & 1) == 1 {
-                        self.p.set_c();
-                    } else {
-                        self.p.unset_c();
-                    }
+            // DEC
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let value = self.mem_read_byte(addr);
+                let result = value.wrapping_sub(1);
 
-                    value >>= 1;
-                    match &opcode.mode {
-                        AddressingMode::NoneAddressing => {
-                            self.a = value;
-                        }
-                        _ => {
-                            let addr = self.get_operand_address(&opcode.mode);
-                            self.mem_write_byte(addr, value);
-                        }
+                self.mem_write_byte(addr, result);
+                self.p.ensure_z(result);
+                self.p.ensure_n(result);
+            }
+
+            // EOR
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                let value = self.read_operand(&opcode.mode);
+
+                self.a ^= value;
+                self.p.ensure_z(self.a);
+                self.p.ensure_n(self.a);
+            }
+
+            // LSR
+            0x4a | 0x46 | 0x56 | 0x4e | 0x5e => {
+                let mut value = match &opcode.mode {
+                    AddressingMode::NoneAddressing => self.a,
+                    _ => {
+                        let addr = self.get_operand_address(&opcode.mode);
+                        self.mem_read_byte(addr)
                     }
+                };
 
-                    self.p.ensure_z(value);
-                    self.p.ensure_n(value);
+                if (value & 1) == 1 {
+                    self.p.set_c();
+                } else {
+                    self.p.unset_c();
                 }
 
-                // ORA
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
-
-                    self.a |= value;
-                    self.p.ensure_z(self.a);
-                    self.p.ensure_n(self.a);
+                value >>= 1;
+                match &opcode.mode {
+                    AddressingMode::NoneAddressing => {
+                        self.a = value;
+                    }
+                    _ => {
+                        let addr = self.get_operand_address(&opcode.mode);
+                        self.mem_write_byte(addr, value);
+                    }
                 }
 
-                // ROL
-                0x2a | 0x26 | 0x36 | 0x2e | 0x3e => {
-                    let value = match &opcode.mode {
-                        AddressingMode::NoneAddressing => self.a,
-                        _ => {
-                            let addr = self.get_operand_address(&opcode.mode);
-                            self.mem_read_byte(addr)
-                        }
-                    };
+                self.p.ensure_z(value);
+                self.p.ensure_n(value);
+            }
 
-                    let did_carry = *self.p.C();
+            // ORA
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                let value = self.read_operand(&opcode.mode);
 
-                    if (value & 0b10000000) == 0b10000000 {
-                        self.p.set_c();
-                    } else {
-                        self.p.unset_c();
-                    }
-
-                    let result = if did_carry {
-                        (value << 1) | 1
-                    } else {
-                        value << 1
-                    };
+                self.a |= value;
+                self.p.ensure_z(self.a);
+                self.p.ensure_n(self.a);
+            }
 
-                    match &opcode.mode {
-                        AddressingMode::NoneAddressing => {
-                            self.a = result;
-                        }
-                        _ => {
-                            let addr = self.get_operand_address(&opcode.mode);
-                            self.mem_write_byte(addr, result);
-                        }
+            // ROL
+            0x2a | 0x26 | 0x36 | 0x2e | 0x3e => {
+                let value = match &opcode.mode {
+                    AddressingMode::NoneAddressing => self.a,
+                    _ => {
+                        let addr = self.get_operand_address(&opcode.mode);
+                        self.mem_read_byte(addr)
                     }
+                };
 
-                    self.p.ensure_z(result);
-                    self.p.ensure_n(result);
-                }
+                let did_carry = *self.p.C();
 
-                // ROR
-                0x6a | 0x66 | 0x76 | 0x6e | 0x7e => {
-                    let value = match &opcode.mode {
-                        AddressingMode::NoneAddressing => self.a,
-                        _ => {
-                            let addr = self.get_operand_address(&opcode.mode);
-                            self.mem_read_byte(addr)
-                        }
-                    };
+                if (value & 0b10000000) == 0b10000000 {
+                    self.p.set_c();
+                } else {
+                    self.p.unset_c();
+                }
 
-                    let did_carry = *self.p.C();
+                let result = if did_carry {
+                    (value << 1) | 1
+                } else {
+                    value << 1
+                };
 
-                    if (value & 1) == 1 {
-                        self.p.set_c();
-                    } else {
-                        self.p.unset_c();
+                match &opcode.mode {
+                    AddressingMode::NoneAddressing => {
+                        self.a = result;
                     }
+                    _ => {
+                        let addr = self.get_operand_address(&opcode.mode);
+                        self.mem_write_byte(addr, result);
+                    }
+                }
 
-                    let result = if did_carry {
-                        (value >> 1) | 0b10000000
-                    } else {
-                        value >> 1
-                    };
+                self.p.ensure_z(result);
+                self.p.ensure_n(result);
+            }
 
-                    match &opcode.mode {
-                        AddressingMode::NoneAddressing => {
-                            self.a = result;
-                        }
-                        _ => {
-                            let addr = self.get_operand_address(&opcode.mode);
-                            self.mem_write_byte(addr, result);
-                        }
+            // ROR
+            0x6a | 0x66 | 0x76 | 0x6e | 0x7e => {
+                let value = match &opcode.mode {
+                    AddressingMode::NoneAddressing => self.a,
+                    _ => {
+                        let addr = self.get_operand_address(&opcode.mode);
+                        self.mem_read_byte(addr)
                     }
+                };
 
-                    self.p.ensure_z(result);
-                    self.p.ensure_n(result);
+                let did_carry = *self.p.C();
+
+                if (value & 1) == 1 {
+                    self.p.set_c();
+                } else {
+                    self.p.unset_c();
                 }
 
-                // SBC
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
-                    let carry = if *self.p.C() { 1u8 } else { 0u8 };
+                let result = if did_carry {
+                    (value >> 1) | 0b10000000
+                } else {
+                    value >> 1
+                };
+
+                match &opcode.mode {
+                    AddressingMode::NoneAddressing => {
+                        self.a = result;
+                    }
+                    _ => {
+                        let addr = self.get_operand_address(&opcode.mode);
+                        self.mem_write_byte(addr, result);
+                    }
+                }
 
-                    if (self.a ^ value) & 0x80 == 0x80 {
-                        self.p.set_v();
+                self.p.ensure_z(result);
+                self.p.ensure_n(result);
+            }
+
+            // SBC
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
+                let value = self.read_operand(&opcode.mode);
+                let carry = if *self.p.C() { 1u8 } else { 0u8 };
+
+                if (self.a ^ value) & 0x80 == 0x80 {
+                    self.p.set_v();
+                } else {
+                    self.p.unset_v();
+                }
+
+                let mut result: i16 = 0;
+                if *self.p.D() {
+                    let mut low =
+                        0x0f + (self.a & 0x0f) as i16 - (value & 0x0f) as i16 + carry as i16;
+                    if low < 0x10 {
+                        low -= 0x6;
                     } else {
-                        self.p.unset_v();
+                        result = 0x10;
+                        low -= 0x10;
                     }
 
-                    let mut result: i16 = 0;
-                    if *self.p.D() {
-                        let mut low =
-                            0x0f + (self.a & 0x0f) as i16 - (value & 0x0f) as i16 + carry as i16;
-                        if low < 0x10 {
-                            low -= 0x6;
-                        } else {
-                            result = 0x10;
-                            low -= 0x10;
+                    result += 0xf0 + (self.a & 0xf0) as i16 - (value & 0xf0) as i16;
+                    if result < 0x100 {
+                        self.p.unset_c();
+                        if result < 0x80 {
+                            self.p.unset_v();
                         }
-
-                        result += 0xf0 + (self.a & 0xf0) as i16 - (value & 0xf0) as i16;
-                        if result < 0x100 {
-                            self.p.unset_c();
-                            if result < 0x80 {
-                                self.p.unset_v();
-                            }
-                            result -= 0x60;
-                        } else {
-                            self.p.set_c();
-                            if result >= 0x180 {
-                                self.p.unset_v();
-                            }
+                        result -= 0x60;
+                    } else {
+                        self.p.set_c();
+                        if result >= 0x180 {
+                            self.p.unset_v();
                         }
+                    }
 
-                        result += low;
+                    result += low;
+                } else {
+                    result = 0xff + self.a as i16 - value as i16 + carry as i16;
+                    if result < 0x100 {
+                        self.p.unset_c();
+                        if result < 0x80 {
+                            self.p.unset_v();
+                        }
                     } else {
-                        result = 0xff + self.a as i16 - value as i16 + carry as i16;
-                        if result < 0x100 {
-                            self.p.unset_c();
-                            if result < 0x80 {
-                                self.p.unset_v();
-                            }
-                        } else {
-                            self.p.set_c();
-                            if result >= 0x180 {
-                                self.p.unset_v();
-                            }
+                        self.p.set_c();
+                        if result >= 0x180 {
+                            self.p.unset_v();
                         }
                     }
-
-                    self.a = result as u8;
-                    self.p.ensure_z(self.a);
-                    self.p.ensure_n(self.a);
                 }
 
-                // Status register
-                // CLC
-                0x18 => self.p.unset_c(),
+                self.a = result as u8;
+                self.p.ensure_z(self.a);
+                self.p.ensure_n(self.a);
+            }
 
-                // CLD
-                0xd8 => self.p.unset_d(),
+            // Status register
+            // CLC
+            0x18 => self.p.unset_c(),
 
-                // CLI
-                0x58 => self.p.unset_i(),
+            // CLD
+            0xd8 => self.p.unset_d(),
 
-                // CLV
-                0xb8 => self.p.unset_v(),
+            // CLI
+            0x58 => self.p.unset_i(),
 
-                // SEC
-                0x38 => self.p.set_c(),
+            // CLV
+            0xb8 => self.p.unset_v(),
 
-                // SED
-                0xf8 => self.p.set_d(),
+            // SEC
+            0x38 => self.p.set_c(),
 
-                // SEI
-                0x78 => self.p.set_i(),
+            // SED
+            0xf8 => self.p.set_d(),
 
-                // Stack related
-                // PHA
-                0x48 => self.stack_push_byte(self.a),
+            // SEI
+            0x78 => self.p.set_i(),
 
-                // PHP
-                0x08 => {
-                    let mut flags = self.p.clone();
-                    flags.set_b();
-                    flags.set_b2();
-                    self.stack_push_byte(flags.pack());
-                }
+            // Stack related
+            // PHA
+            0x48 => self.stack_push_byte(self.a),
 
-                // PLA
-                0x68 => {
-                    self.a = self.stack_pop_byte();
-                    self.p.ensure_z(self.a);
-                    self.p.ensure_n(self.a);
-                }
+            // PHP
+            0x08 => {
+                let mut flags = self.p.clone();
+                flags.set_b();
+                flags.set_b2();
+                self.stack_push_byte(flags.pack());
+            }
 
-                // PLP
-                0x28 => {
-                    self.p = StatusRegister::new(self.stack_pop_byte());
-                    self.p.unset_b();
-                    self.p.set_b2();
-                }
+            // PLA
+            0x68 => {
+                self.a = self.stack_pop_byte();
+                self.p.ensure_z(self.a);
+                self.p.ensure_n(self.a);
+            }
 
-                // Interrupts
-                // BRK
-                0x00 => {
-                    if !self.p.I() {
-                        let handler_addr = self.mem_read_word(MemLayout::IRQ_VECTOR);
-                        if handler_addr == 0 {
-                            return;
-                        }
+            // PLP
+            0x28 => {
+                self.p = StatusRegister::new(self.stack_pop_byte());
+                self.p.unset_b();
+                self.p.set_b2();
+            }
 
-                        self.stack_push_word(self.pc);
-                        self.p.set_b();
-                        self.p.set_b2();
-                        self.stack_push_byte(self.p.pack());
-                        self.p.set_i();
-                        self.pc = handler_addr;
+            // Interrupts
+            // BRK
+            0x00 => {
+                if self.mem_read_byte(self.pc) == SYSCALL_SIGNATURE {
+                    self.pc += 1;
+                    self.syscall();
+                } else if !self.p.I() {
+                    let handler_addr = self.mem_read_word(MemLayout::IRQ_VECTOR);
+                    if handler_addr == 0 {
+                        return false;
                     }
-                }
-                // RTI
-                0x40 => {
-                    self.p = StatusRegister::new(self.stack_pop_byte());
-                    self.p.unset_b();
+
+                    self.stack_push_word(self.pc);
+                    self.p.set_b();
                     self.p.set_b2();
-                    self.pc = self.stack_pop_word();
+                    self.stack_push_byte(self.p.pack());
+                    self.p.set_i();
+                    self.pc = handler_addr;
                 }
-                // NOP
-                0xEA => {}
-
-                // A,X,Y registers
-                // CPX
-                0xe0 | 0xe4 | 0xec => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
-                    if value <= self.x {
-                        self.p.set_c();
-                    } else {
-                        self.p.unset_c();
-                    }
-
-                    self.p.ensure_n(self.x.wrapping_sub(value));
-                    self.p.ensure_z(self.x.wrapping_sub(value));
+            }
+            // RTI
+            0x40 => {
+                self.p = StatusRegister::new(self.stack_pop_byte());
+                self.p.unset_b();
+                self.p.set_b2();
+                self.pc = self.stack_pop_word();
+            }
+            // NOP
+            0xEA => {}
+
+            // A,X,Y registers
+            // CPX
+            0xe0 | 0xe4 | 0xec => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let value = self.mem_read_byte(addr);
+                if value <= self.x {
+                    self.p.set_c();
+                } else {
+                    self.p.unset_c();
                 }
 
-                // CPY
-                0xc0 | 0xc4 | 0xcc => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
-                    if value <= self.y {
-                        self.p.set_c();
-                    } else {
-                        self.p.unset_c();
-                    }
-
-                    self.p.ensure_n(self.y.wrapping_sub(value));
-                    self.p.ensure_z(self.y.wrapping_sub(value));
-                }
+                self.p.ensure_n(self.x.wrapping_sub(value));
+                self.p.ensure_z(self.x.wrapping_sub(value));
+            }
 
-                // DEX
-                0xca => {
-                    self.x = self.x.wrapping_sub(1);
-                    self.p.ensure_n(self.x);
-                    self.p.ensure_z(self.x);
+            // CPY
+            0xc0 | 0xc4 | 0xcc => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let value = self.mem_read_byte(addr);
+                if value <= self.y {
+                    self.p.set_c();
+                } else {
+                    self.p.unset_c();
                 }
 
-                // DEY
-                0x88 => {
-                    self.y = self.y.wrapping_sub(1);
-                    self.p.ensure_n(self.y);
-                    self.p.ensure_z(self.y)
-                }
+                self.p.ensure_n(self.y.wrapping_sub(value));
+                self.p.ensure_z(self.y.wrapping_sub(value));
+            }
 
-                // INC
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr).wrapping_add(1);
+            // DEX
+            0xca => {
+                self.x = self.x.wrapping_sub(1);
+                self.p.ensure_n(self.x);
+                self.p.ensure_z(self.x);
+            }
 
-                    self.mem_write_byte(addr, value);
-                    self.p.ensure_z(value);
-                    self.p.ensure_n(value);
-                }
+            // DEY
+            0x88 => {
+                self.y = self.y.wrapping_sub(1);
+                self.p.ensure_n(self.y);
+                self.p.ensure_z(self.y)
+            }
 
-                // INX
-                0xE8 => {
-                    self.x = self.x.wrapping_add(1);
-                    self.p.ensure_z(self.x);
-                    self.p.ensure_n(self.x);
-                }
+            // INC
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                let addr = self.get_operand_address(&opcode.mode);
+                let value = self.mem_read_byte(addr).wrapping_add(1);
 
-                // INY
-                0xc8 => {
-                    self.y = self.y.wrapping_add(1);
-                    self.p.ensure_z(self.y);
-                    self.p.ensure_n(self.y);
-                }
+                self.mem_write_byte(addr, value);
+                self.p.ensure_z(value);
+                self.p.ensure_n(value);
+            }
 
-                // LDA
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
+            // INX
+            0xE8 => {
+                self.x = self.x.wrapping_add(1);
+                self.p.ensure_z(self.x);
+                self.p.ensure_n(self.x);
+            }
 
-                    self.a = value;
-                    self.p.ensure_z(self.a);
-                    self.p.ensure_n(self.a);
-                }
+            // INY
+            0xc8 => {
+                self.y = self.y.wrapping_add(1);
+                self.p.ensure_z(self.y);
+                self.p.ensure_n(self.y);
+            }
 
-                // LDX
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
+            // LDA
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                let value = self.read_operand(&opcode.mode);
 
-                    self.x = value;
-                    self.p.ensure_z(self.x);
-                    self.p.ensure_n(self.x);
-                }
+                self.a = value;
+                self.p.ensure_z(self.a);
+                self.p.ensure_n(self.a);
+            }
 
-                // LDY
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    let value = self.mem_read_byte(addr);
+            // LDX
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                let value = self.read_operand(&opcode.mode);
 
-                    self.y = value;
-                    self.p.ensure_z(self.y);
-                    self.p.ensure_n(self.y);
-                }
+                self.x = value;
+                self.p.ensure_z(self.x);
+                self.p.ensure_n(self.x);
+            }
 
-                // STA
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write_byte(addr, self.a);
-                }
+            // LDY
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                let value = self.read_operand(&opcode.mode);
 
-                // STX
-                0x86 | 0x96 | 0x8e => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write_byte(addr, self.x);
-                }
+                self.y = value;
+                self.p.ensure_z(self.y);
+                self.p.ensure_n(self.y);
+            }
 
-                // STY
-                0x84 | 0x94 | 0x8c => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write_byte(addr, self.y);
-                }
+            // STA
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_write_byte(addr, self.a);
+            }
 
-                // TAX
-                0xAA => {
-                    self.x = self.a;
-                    self.p.ensure_z(self.x);
-                    self.p.ensure_n(self.x);
-                }
+            // STX
+            0x86 | 0x96 | 0x8e => {
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_write_byte(addr, self.x);
+            }
 
-                // TAY
-                0xA8 => {
-                    self.y = self.a;
-                    self.p.ensure_z(self.y);
-                    self.p.ensure_n(self.y);
-                }
+            // STY
+            0x84 | 0x94 | 0x8c => {
+                let addr = self.get_operand_address(&opcode.mode);
+                self.mem_write_byte(addr, self.y);
+            }
 
-                // TSX
-                0xBA => {
-                    self.x = (self.sp & 0xff) as u8;
-                    self.p.ensure_z(self.x);
-                    self.p.ensure_n(self.x);
-                }
+            // TAX
+            0xAA => {
+                self.x = self.a;
+                self.p.ensure_z(self.x);
+                self.p.ensure_n(self.x);
+            }
 
-                // TXA
-                0x8a => {
-                    self.a = self.x;
-                    self.p.ensure_z(self.a);
-                    self.p.ensure_n(self.a);
-                }
+            // TAY
+            0xA8 => {
+                self.y = self.a;
+                self.p.ensure_z(self.y);
+                self.p.ensure_n(self.y);
+            }
 
-                // TXS
-                0x9A => {
-                    self.sp = MemLayout::STACK_START | (self.x as u16);
-                }
+            // TSX
+            0xBA => {
+                self.x = (self.sp & 0xff) as u8;
+                self.p.ensure_z(self.x);
+                self.p.ensure_n(self.x);
+            }
 
-                // TYA
-                0x98 => {
-                    self.a = self.y;
-                    self.p.ensure_z(self.a);
-                    self.p.ensure_n(self.a);
-                }
+            // TXA
+            0x8a => {
+                self.a = self.x;
+                self.p.ensure_z(self.a);
+                self.p.ensure_n(self.a);
+            }
 
-                // Control flow
-                // BCC
-                0x90 => {
-                    let condition = !*self.p.C();
-                    if condition {
-                        self.jump_near_immediate();
-                    }
-                }
+            // TXS
+            0x9A => {
+                self.sp = MemLayout::STACK_START | (self.x as u16);
+            }
 
-                // BCS
-                0xb0 => {
-                    let condition = *self.p.C();
-                    if condition {
-                        self.jump_near_immediate();
-                    }
-                }
+            // TYA
+            0x98 => {
+                self.a = self.y;
+                self.p.ensure_z(self.a);
+                self.p.ensure_n(self.a);
+            }
 
-                // BEQ
-                0xf0 => {
-                    let condition = *self.p.Z();
-                    if condition {
-                        self.jump_near_immediate();
-                    }
+            // Control flow
+            // BCC
+            0x90 => {
+                let condition = !*self.p.C();
+                if condition {
+                    self.jump_near_immediate();
                 }
+            }
 
-                // BMI
-                0x30 => {
-                    let condition = *self.p.N();
-                    if condition {
-                        self.jump_near_immediate();
-                    }
+            // BCS
+            0xb0 => {
+                let condition = *self.p.C();
+                if condition {
+                    self.jump_near_immediate();
                 }
+            }
 
-                // BNE
-                0xd0 => {
-                    let condition = !*self.p.Z();
-                    if condition {
-                        self.jump_near_immediate();
-                    }
+            // BEQ
+            0xf0 => {
+                let condition = *self.p.Z();
+                if condition {
+                    self.jump_near_immediate();
                 }
+            }
 
-                // BPL
-                0x10 => {
-                    let condition = !*self.p.N();
-                    if condition {
-                        self.jump_near_immediate();
-                    }
+            // BMI
+            0x30 => {
+                let condition = *self.p.N();
+                if condition {
+                    self.jump_near_immediate();
                 }
+            }
 
-                // BVC
-                0x50 => {
-                    let condition = !*self.p.V();
-                    if condition {
-                        self.jump_near_immediate();
-                    }
+            // BNE
+            0xd0 => {
+                let condition = !*self.p.Z();
+                if condition {
+                    self.jump_near_immediate();
                 }
+            }
 
-                // BVS
-                0x70 => {
-                    let condition = *self.p.V();
-                    if condition {
-                        self.jump_near_immediate();
-                    }
+            // BPL
+            0x10 => {
+                let condition = !*self.p.N();
+                if condition {
+                    self.jump_near_immediate();
                 }
+            }
 
-                // JMP
-                0x4c | 0x6c => {
-                    self.pc = self.get_operand_address(&opcode.mode);
+            // BVC
+            0x50 => {
+                let condition = !*self.p.V();
+                if condition {
+                    self.jump_near_immediate();
                 }
+            }
 
-                // JSR
-                0x20 => {
-                    self.stack_push_word(self.pc + 2);
-                    self.pc = self.get_operand_address(&opcode.mode);
+            // BVS
+            0x70 => {
+                let condition = *self.p.V();
+                if condition {
+                    self.jump_near_immediate();
                 }
+            }
 
-                // RTS
-                0x60 => {
-                    self.pc = self.stack_pop_word();
-                }
+            // JMP
+            0x4c | 0x6c => {
+                self.pc = self.get_operand_address(&opcode.mode);
+            }
 
-                _ => panic!("SIGILL: Not Implemented"),
+            // JSR
+            0x20 => {
+                self.stack_push_word(self.pc + 2);
+                self.pc = self.get_operand_address(&opcode.mode);
             }
 
-            if pc_bak == self.pc {
-                self.pc += (opcode.len - 1) as u16;
+            // RTS
+            0x60 => {
+                self.pc = self.stack_pop_word();
             }
 
-            callback(self);
+            _ => panic!("SIGILL: Not Implemented"),
         }
+
+        if pc_bak == self.pc {
+            self.pc += (opcode.len - 1) as u16;
+        }
+
+        self.call_stack.track(
+            opcode.mnemonic,
+            opcode.code,
+            start_pc,
+            (start_sp, self.sp),
+            self.pc,
+            &self.bus,
+        );
+
+        self.bus
+            .tick(opcode.cycles + mem::take(&mut self.extra_cycles));
+
+        self.service_interrupts();
+
+        self.bus.exit_code().is_none()
     }
 }
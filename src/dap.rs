@@ -0,0 +1,702 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::cpu::CPU;
+use crate::debug_info::DebugInfo;
+use crate::debugger::expression::Expression;
+use crate::debugger::{BreakReason, Breakpoint, Debugger, HitCondition, LogMessage, Stop, Trigger};
+use crate::disasm::{disassemble, instructions_around};
+
+// Check for requests from the client once per this many instructions while running
+const REQUEST_CHECK_INTERVAL: u32 = 4096;
+
+// Far more than any request needs, e.g. writing all 64KB of memory in base64
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+const THREAD_ID: u64 = 1;
+
+// Variable references of the scopes
+const REGISTERS: u64 = 1;
+const FLAGS: u64 = 2;
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (idx, byte)| {
+            group | (*byte as u32) << (16 - 8 * idx)
+        });
+
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * idx) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+// Memory references are addresses like `0x0200` or `$0200`
+fn address(reference: &str) -> Option<u16> {
+    match reference
+        .strip_prefix("0x")
+        .or_else(|| reference.strip_prefix('$'))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+fn source(file: &Path) -> Value {
+    json!({
+        "name": file.file_name().map(|name| name.to_string_lossy()),
+        "path": file.to_string_lossy(),
+    })
+}
+
+// Applies the condition, hit condition and log message of a requested breakpoint
+fn configure(mut bp: Breakpoint, requested: &Value) -> Result<Breakpoint, String> {
+    let field = |name: &str| requested[name].as_str().filter(|s| !s.trim().is_empty());
+
+    if let Some(condition) = field("condition") {
+        bp.condition = Some(Expression::parse(condition)?);
+    }
+
+    if let Some(hit_condition) = field("hitCondition") {
+        bp.hit_condition = Some(HitCondition::parse(hit_condition)?);
+    }
+
+    if let Some(message) = field("logMessage") {
+        bp.log = Some(LogMessage::parse(message)?);
+    }
+
+    Ok(bp)
+}
+
+#[derive(Clone, Copy)]
+enum Resume {
+    Continue,
+    Instruction,
+    // Until another source line is reached, running through subroutines if `over` is set
+    Line { over: bool },
+    // Until the current subroutine or interrupt handler returns
+    Out,
+}
+
+enum Outcome {
+    Stopped(&'static str),
+    Break(BreakReason),
+    Halted,
+    Disconnected,
+}
+
+enum Command {
+    None,
+    Resume(Resume),
+    Pause,
+    Disconnect,
+}
+
+// A Debug Adapter Protocol connection controlling the CPU
+pub struct DapServer {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+    seq: u64,
+    debug_info: DebugInfo,
+    debugger: Debugger,
+    // Breakpoint IDs of every source file, and of the data breakpoints
+    breakpoints: HashMap<PathBuf, Vec<u32>>,
+    data_breakpoints: Vec<u32>,
+    stop_on_entry: bool,
+    launched: bool,
+    configured: bool,
+    exited: bool,
+}
+
+// Waits for a client to connect, then runs the program under its control until it disconnects
+pub fn serve(cpu: &mut CPU, listener: TcpListener, debug_info: DebugInfo) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    DapServer::new(stream, debug_info)?.run(cpu)
+}
+
+impl DapServer {
+    pub fn new(stream: TcpStream, debug_info: DebugInfo) -> io::Result<Self> {
+        Ok(DapServer {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+            seq: 0,
+            debug_info,
+            debugger: Debugger::default(),
+            breakpoints: HashMap::new(),
+            data_breakpoints: vec![],
+            stop_on_entry: false,
+            launched: false,
+            configured: false,
+            exited: false,
+        })
+    }
+
+    // Returns None once the client has disconnected
+    fn read_message(&mut self) -> io::Result<Option<Value>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.reader.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+
+        let length = length
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length"))?;
+        if length > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Message of {} bytes is too long", length),
+            ));
+        }
+
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body)?;
+
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+
+        let body = message.to_string();
+        write!(
+            self.stream,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.stream.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+
+        self.send(response)
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    fn break_stopped(&mut self, reason: BreakReason) -> io::Result<()> {
+        let kind = match reason.event {
+            Trigger::Execute => "breakpoint",
+            _ => "data breakpoint",
+        };
+
+        self.event(
+            "stopped",
+            json!({
+                "reason": kind,
+                "description": reason.to_string(),
+                "hitBreakpointIds": [reason.id],
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        )
+    }
+
+    // Whether the client sent a request since the last check, without blocking
+    fn request_pending(&mut self) -> io::Result<bool> {
+        if !self.reader.buffer().is_empty() {
+            return Ok(true);
+        }
+
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.peek(&mut [0]);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn location(&self, addr: u16) -> Option<(PathBuf, u32)> {
+        self.debug_info
+            .location(addr)
+            .map(|(file, line)| (file.to_path_buf(), line))
+    }
+
+    fn resume(&mut self, cpu: &mut CPU, mode: Resume) -> io::Result<Outcome> {
+        let start = self.location(*cpu.pc());
+        let start_depth = cpu.call_stack().depth();
+
+        let mut count = 0u32;
+        loop {
+            let stop = self.debugger.step(cpu);
+            let mut messages = self.debugger.take_logs();
+            messages.extend(cpu.call_stack_mut().take_warnings());
+            for message in messages {
+                let output = format!("{}\n", message);
+                self.event("output", json!({ "category": "console", "output": output }))?;
+            }
+
+            match stop {
+                Some(Stop::Halted) => return Ok(Outcome::Halted),
+                Some(Stop::Break(reason)) => return Ok(Outcome::Break(reason)),
+                None => {}
+            }
+
+            let done = match mode {
+                Resume::Continue => false,
+                Resume::Instruction => true,
+                Resume::Line { over } => {
+                    let location = self.location(*cpu.pc());
+                    (!over || cpu.call_stack().depth() <= start_depth)
+                        && (start.is_none() || location.is_some() && location != start)
+                }
+                Resume::Out => cpu.call_stack().depth() < start_depth,
+            };
+
+            if done {
+                return Ok(Outcome::Stopped("step"));
+            }
+
+            count = count.wrapping_add(1);
+            if count.is_multiple_of(REQUEST_CHECK_INTERVAL) && self.request_pending()? {
+                let request = match self.read_message()? {
+                    Some(request) => request,
+                    None => return Ok(Outcome::Disconnected),
+                };
+
+                match self.handle(cpu, &request)? {
+                    Command::Pause => return Ok(Outcome::Stopped("pause")),
+                    Command::Disconnect => return Ok(Outcome::Disconnected),
+                    Command::None | Command::Resume(_) => {}
+                }
+            }
+        }
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or_else(|| String::from("Breakpoints need a source path"))?;
+        let path = PathBuf::from(path);
+
+        for id in self.breakpoints.remove(&path).unwrap_or_default() {
+            self.debugger.remove(id);
+        }
+
+        let mut ids = vec![];
+        let mut breakpoints = vec![];
+        for requested in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = requested["line"].as_u64().unwrap_or(0) as u32;
+            let (line, addrs) = match self.debug_info.addresses(&path, line) {
+                Some(found) => found,
+                None => {
+                    breakpoints.push(json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "No code at this line",
+                    }));
+                    continue;
+                }
+            };
+
+            // A line assembled more than once, e.g. by a macro, gets a breakpoint at every copy
+            let added = addrs
+                .iter()
+                .map(|addr| {
+                    let bp = configure(Breakpoint::at(*addr), requested)?;
+                    Ok(self.debugger.add(bp))
+                })
+                .collect::<Result<Vec<_>, String>>();
+
+            match added {
+                Ok(added) => {
+                    breakpoints.push(json!({ "id": added[0], "verified": true, "line": line }));
+                    ids.extend(added);
+                }
+                Err(message) => breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": message,
+                })),
+            }
+        }
+
+        self.breakpoints.insert(path, ids);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // Data breakpoints watch `bytes` bytes from the address given as the name
+    fn data_breakpoint_info(&self, args: &Value) -> Value {
+        let bytes = args["bytes"].as_u64().unwrap_or(1).clamp(1, 0x10000);
+        match args["name"].as_str().and_then(address) {
+            Some(addr) => json!({
+                "dataId": format!("0x{:04X},{}", addr, bytes),
+                "description": format!("${:04X} ({} bytes)", addr, bytes),
+                "accessTypes": ["read", "write", "readWrite"],
+                "canPersist": true,
+            }),
+            None => json!({ "dataId": null, "description": "Not a memory address" }),
+        }
+    }
+
+    fn set_data_breakpoints(&mut self, args: &Value) -> Value {
+        for id in self.data_breakpoints.drain(..) {
+            self.debugger.remove(id);
+        }
+
+        let mut breakpoints = vec![];
+        for requested in args["breakpoints"].as_array().into_iter().flatten() {
+            let trigger = match requested["accessType"].as_str() {
+                Some("read") => Trigger::Read,
+                Some("readWrite") => Trigger::Access,
+                _ => Trigger::Write,
+            };
+
+            let range = requested["dataId"].as_str().and_then(|id| {
+                let (addr, bytes) = id.split_once(',')?;
+                let start = address(addr)?;
+                let bytes = bytes.parse::<u16>().ok()?;
+                Some((start, start.wrapping_add(bytes.max(1) - 1)))
+            });
+
+            let added = match range {
+                Some((start, end)) => configure(Breakpoint::new(trigger, start, end), requested)
+                    .map(|bp| self.debugger.add(bp)),
+                None => Err(String::from("Invalid data breakpoint")),
+            };
+
+            match added {
+                Ok(id) => {
+                    self.data_breakpoints.push(id);
+                    breakpoints.push(json!({ "id": id, "verified": true }));
+                }
+                Err(message) => breakpoints.push(json!({ "verified": false, "message": message })),
+            }
+        }
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    // One frame per call in progress, following the shadow call stack of the CPU
+    fn stack_trace(&self, cpu: &CPU) -> Value {
+        let frames = cpu
+            .call_stack()
+            .backtrace(*cpu.pc())
+            .into_iter()
+            .enumerate()
+            .map(|(id, level)| {
+                let mut frame = json!({
+                    "id": id,
+                    "name": self.debug_info.describe(level.addr),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:04X}", level.addr),
+                });
+
+                if let Some((file, line)) = self.debug_info.location(level.addr) {
+                    frame["source"] = source(file);
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+
+                frame
+            })
+            .collect::<Vec<_>>();
+
+        json!({ "totalFrames": frames.len(), "stackFrames": frames })
+    }
+
+    fn variables(&self, cpu: &CPU, reference: u64) -> Value {
+        let byte = |name: &str, value: u8| json!({ "name": name, "value": format!("${:02X}", value), "variablesReference": 0 });
+        let word = |name: &str, value: u16| {
+            json!({
+                "name": name,
+                "value": format!("${:04X}", value),
+                "memoryReference": format!("0x{:04X}", value),
+                "variablesReference": 0,
+            })
+        };
+
+        let p = cpu.p();
+        let variables = match reference {
+            REGISTERS => vec![
+                byte("A", *cpu.a()),
+                byte("X", *cpu.x()),
+                byte("Y", *cpu.y()),
+                byte("P", p.pack()),
+                word("SP", *cpu.sp()),
+                word("PC", *cpu.pc()),
+            ],
+            FLAGS => [
+                ("N", *p.N()),
+                ("V", *p.V()),
+                ("B", *p.B()),
+                ("D", *p.D()),
+                ("I", *p.I()),
+                ("Z", *p.Z()),
+                ("C", *p.C()),
+            ]
+            .iter()
+            .map(|(name, set)| json!({ "name": name, "value": (*set as u8).to_string(), "variablesReference": 0 }))
+            .collect(),
+            _ => vec![],
+        };
+
+        json!({ "variables": variables })
+    }
+
+    fn read_memory(&self, cpu: &CPU, args: &Value) -> Result<Value, String> {
+        let base = args["memoryReference"]
+            .as_str()
+            .and_then(address)
+            .ok_or_else(|| String::from("Invalid memory reference"))?;
+        let start = base as i64 + args["offset"].as_i64().unwrap_or(0);
+        let count = args["count"].as_u64().unwrap_or(0) as i64;
+
+        // Bytes outside of the address space are unreadable
+        let end = (start + count).clamp(0, 0x10000);
+        let start = start.clamp(0, end);
+        let bytes = (start..end)
+            .map(|addr| cpu.bus().peek(addr as u16))
+            .collect::<Vec<_>>();
+
+        Ok(json!({
+            "address": format!("0x{:04X}", start),
+            "data": base64(&bytes),
+            "unreadableBytes": count - bytes.len() as i64,
+        }))
+    }
+
+    // `instructionCount` instructions from `instructionOffset` instructions after the
+    // instruction at the memory reference, which may be negative
+    fn disassemble(&self, cpu: &CPU, args: &Value) -> Result<Value, String> {
+        let base = args["memoryReference"]
+            .as_str()
+            .and_then(address)
+            .ok_or_else(|| String::from("Invalid memory reference"))?;
+        let base = base.wrapping_add(args["offset"].as_i64().unwrap_or(0) as u16);
+        let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        // The whole address space has fewer instructions than this
+        let count = args["instructionCount"].as_u64().unwrap_or(0).min(0x10000) as i64;
+
+        // Looking further back for instructions which line up gets slow
+        let before = offset.clamp(-0x100, 0).unsigned_abs() as usize;
+        let after = (offset + count).clamp(0, 0x10000) as usize;
+        let addrs = instructions_around(cpu.bus(), base, before, before + 1 + after);
+
+        // Before the instructions which line up with the memory reference, bytes are
+        // disassembled one by one
+        let found = addrs.iter().position(|addr| *addr == base).unwrap_or(0);
+        let first = addrs.first().copied().unwrap_or(base);
+        let index = |n: i64| match found as i64 + n {
+            idx if idx >= 0 => addrs[idx as usize],
+            idx => first.wrapping_sub(idx.unsigned_abs() as u16),
+        };
+
+        let instructions = (offset..offset + count)
+            .map(|n| {
+                let addr = index(n);
+                let (text, len) = disassemble(cpu.bus(), addr);
+                let bytes = (0..len)
+                    .map(|idx| format!("{:02X}", cpu.bus().peek(addr.wrapping_add(idx))))
+                    .collect::<Vec<_>>();
+
+                let mut instruction = json!({
+                    "address": format!("0x{:04X}", addr),
+                    "instructionBytes": bytes.join(" "),
+                    "instruction": text,
+                });
+
+                if let Some((label, 0)) = self.debug_info.symbol(addr) {
+                    instruction["symbol"] = json!(label);
+                }
+
+                if let Some((file, line)) = self.debug_info.location(addr) {
+                    instruction["location"] = source(file);
+                    instruction["line"] = json!(line);
+                }
+
+                instruction
+            })
+            .collect::<Vec<_>>();
+
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, request: &Value) -> io::Result<Command> {
+        let args = &request["arguments"];
+        let mut command = Command::None;
+        let result = match request["command"].as_str().unwrap_or_default() {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsReadMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsHitConditionalBreakpoints": true,
+                "supportsLogPoints": true,
+                "supportsDataBreakpoints": true,
+            })),
+            "launch" => {
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                let loaded = match args["debugInfo"].as_str() {
+                    Some(path) => DebugInfo::load(Path::new(path)).map(|info| {
+                        self.debug_info = info;
+                    }),
+                    None => Ok(()),
+                };
+
+                loaded.map(|_| {
+                    self.launched = true;
+                    Value::Null
+                })
+            }
+            "configurationDone" => {
+                self.configured = true;
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "dataBreakpointInfo" => Ok(self.data_breakpoint_info(args)),
+            "setDataBreakpoints" => Ok(self.set_data_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CPU" }] })),
+            "stackTrace" => Ok(self.stack_trace(cpu)),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS, "expensive": false },
+            ]})),
+            "variables" => {
+                Ok(self.variables(cpu, args["variablesReference"].as_u64().unwrap_or(0)))
+            }
+            "readMemory" => self.read_memory(cpu, args),
+            "disassemble" => self.disassemble(cpu, args),
+            "continue" => {
+                command = Command::Resume(Resume::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            name @ ("next" | "stepIn" | "stepOut") => {
+                let mode = match (name, args["granularity"].as_str()) {
+                    (_, Some("instruction")) => Resume::Instruction,
+                    ("stepOut", _) => Resume::Out,
+                    (_, _) => Resume::Line {
+                        over: name == "next",
+                    },
+                };
+
+                command = Command::Resume(mode);
+                Ok(Value::Null)
+            }
+            "pause" => {
+                command = Command::Pause;
+                Ok(Value::Null)
+            }
+            "disconnect" => {
+                command = Command::Disconnect;
+                Ok(Value::Null)
+            }
+            other => Err(format!("Unsupported request: {}", other)),
+        };
+
+        self.respond(request, result)?;
+        if let "initialize" = request["command"].as_str().unwrap_or_default() {
+            self.event("initialized", json!({}))?;
+        }
+
+        Ok(command)
+    }
+
+    fn exit(&mut self, cpu: &CPU) -> io::Result<()> {
+        self.exited = true;
+        self.event(
+            "exited",
+            json!({ "exitCode": cpu.exit_code().unwrap_or(0) }),
+        )?;
+        self.event("terminated", json!({}))
+    }
+
+    fn start(&mut self, cpu: &mut CPU) -> io::Result<bool> {
+        if self.stop_on_entry {
+            self.stopped("entry")?;
+            Ok(true)
+        } else {
+            self.run_until_stop(cpu, Resume::Continue)
+        }
+    }
+
+    // Returns false once the client asked to disconnect
+    fn run_until_stop(&mut self, cpu: &mut CPU, mode: Resume) -> io::Result<bool> {
+        if self.exited {
+            return Ok(true);
+        }
+
+        match self.resume(cpu, mode)? {
+            Outcome::Stopped(reason) => self.stopped(reason).map(|_| true),
+            Outcome::Break(reason) => self.break_stopped(reason).map(|_| true),
+            Outcome::Halted => self.exit(cpu).map(|_| true),
+            Outcome::Disconnected => Ok(false),
+        }
+    }
+
+    pub fn run(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        while let Some(request) = self.read_message()? {
+            let started = self.launched && self.configured;
+            let connected = match self.handle(cpu, &request)? {
+                Command::Disconnect => return Ok(()),
+                Command::Resume(mode) if started => self.run_until_stop(cpu, mode)?,
+                Command::None if !started && self.launched && self.configured => self.start(cpu)?,
+                // Pausing a stopped program only needs the client to refresh its view
+                Command::Pause if started && !self.exited => self.stopped("pause").map(|_| true)?,
+                _ => true,
+            };
+
+            if !connected {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+}
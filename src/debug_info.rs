@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Source locations and labels of a program, one `<addr> <file>:<line>` or `<addr> <label>`
+// entry per line, with the address in hex. The debug info files of the cc65 linker are read
+// as well.
+#[derive(Clone, Default)]
+pub struct DebugInfo {
+    lines: BTreeMap<u16, (PathBuf, u32)>,
+    labels: BTreeMap<u16, String>,
+}
+
+fn address(s: &str) -> Option<u16> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix('$'))
+        .unwrap_or(s);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+// Fields of an entry of a cc65 debug info file, e.g. `file	id=0,name="main.s",size=120`
+fn cc65_fields(entry: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (idx, c) in entry.char_indices().chain(Some((entry.len(), ','))) {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                if let Some((key, value)) = entry[start..idx].split_once('=') {
+                    fields.insert(key, value.trim_matches('"'));
+                }
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+
+    fields
+}
+
+fn cc65_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// The canonical form of a path that exists, the path as given otherwise
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl DebugInfo {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text.starts_with("version\t") {
+            return Self::parse_cc65(text);
+        }
+
+        let mut info = DebugInfo::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || format!("Invalid debug info on line {}: {}", idx + 1, line);
+            let (addr, entry) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let addr = address(addr).ok_or_else(invalid)?;
+            let entry = entry.trim();
+
+            match entry.rsplit_once(':') {
+                Some((file, number)) if number.parse::<u32>().is_ok() => {
+                    let number = number.parse().unwrap();
+                    info.lines.insert(addr, (PathBuf::from(file), number));
+                }
+                _ => {
+                    info.labels.insert(addr, entry.to_string());
+                }
+            }
+        }
+
+        Ok(info)
+    }
+
+    // Reads the `ld65 --dbgfile` output of a program assembled with `ca65 -g`. Source lines map
+    // to spans of bytes in segments, at the address the linker placed the segment.
+    fn parse_cc65(text: &str) -> Result<Self, String> {
+        let mut files = HashMap::new();
+        let mut segments = HashMap::new();
+        let mut spans = HashMap::new();
+        let mut lines = vec![];
+        let mut labels = vec![];
+        for (idx, line) in text.lines().enumerate() {
+            let (kind, entry) = match line.split_once('\t') {
+                Some(parts) => parts,
+                None => continue,
+            };
+
+            let fields = cc65_fields(entry);
+            let invalid = || format!("Invalid debug info on line {}: {}", idx + 1, line);
+            let number = |name: &str| {
+                fields
+                    .get(name)
+                    .and_then(|value| cc65_number(value))
+                    .ok_or_else(invalid)
+            };
+
+            match kind {
+                "file" => {
+                    let name = fields.get("name").ok_or_else(invalid)?;
+                    files.insert(number("id")?, PathBuf::from(name));
+                }
+                "seg" => {
+                    segments.insert(number("id")?, number("start")?);
+                }
+                "span" => {
+                    spans.insert(number("id")?, (number("seg")?, number("start")?));
+                }
+                // Lines inside macro definitions (type 2) would hide the line which expanded them
+                "line" if fields.get("type") != Some(&"2") => {
+                    if let Some(span_ids) = fields.get("span") {
+                        lines.push((number("file")?, number("line")?, span_ids.to_string()));
+                    }
+                }
+                "sym" if fields.get("type") == Some(&"lab") => {
+                    let name = fields.get("name").ok_or_else(invalid)?;
+                    labels.push((number("val")?, name.to_string()));
+                }
+                _ => {}
+            }
+        }
+
+        let mut info = DebugInfo::default();
+        for (file, number, span_ids) in lines {
+            let file = files
+                .get(&file)
+                .ok_or_else(|| format!("Unknown file {} in debug info", file))?;
+            for span_id in span_ids.split('+') {
+                let span = cc65_number(span_id).and_then(|id| spans.get(&id));
+                let addr = span.and_then(|(segment, start)| {
+                    segments
+                        .get(segment)
+                        .map(|segment_start| segment_start + start)
+                });
+                let addr = addr.ok_or_else(|| format!("Unknown span {} in debug info", span_id))?;
+
+                info.lines
+                    .entry(addr as u16)
+                    .or_insert_with(|| (file.clone(), number));
+            }
+        }
+
+        for (addr, label) in labels {
+            info.labels.entry(addr as u16).or_insert(label);
+        }
+
+        Ok(info)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let mut info = Self::parse(&text)?;
+
+        // Source files are relative to the directory of the debug info
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for (file, _) in info.lines.values_mut() {
+            *file = canonical(&dir.join(&file));
+        }
+
+        Ok(info)
+    }
+
+    // The source line the instruction at `addr` was assembled from
+    pub fn location(&self, addr: u16) -> Option<(&Path, u32)> {
+        self.lines
+            .get(&addr)
+            .map(|(file, line)| (file.as_path(), *line))
+    }
+
+    // Addresses of the first instruction of the closest line at or after `line`
+    pub fn addresses(&self, source: &Path, line: u32) -> Option<(u32, Vec<u16>)> {
+        let source = canonical(source);
+        let line = self
+            .lines
+            .values()
+            .filter(|(file, number)| *number >= line && *file == source)
+            .map(|(_, number)| *number)
+            .min()?;
+
+        let addresses = self
+            .lines
+            .iter()
+            .filter(|(_, (file, number))| *number == line && *file == source)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        Some((line, addresses))
+    }
+
+    // The closest label at or before `addr`, with the offset from it
+    pub fn symbol(&self, addr: u16) -> Option<(&str, u16)> {
+        self.labels
+            .range(..=addr)
+            .next_back()
+            .map(|(label_addr, label)| (label.as_str(), addr - label_addr))
+    }
+
+    // `label+offset` for an address after a label, the address in hex otherwise
+    pub fn describe(&self, addr: u16) -> String {
+        match self.symbol(addr) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, offset)) => format!("{}+{}", label, offset),
+            None => format!("${:04X}", addr),
+        }
+    }
+}
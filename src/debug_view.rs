@@ -0,0 +1,208 @@
+use crate::cpu::{MemLayout, CPU};
+use crate::debug_info::DebugInfo;
+use crate::disasm::{disassemble, instructions_around};
+use crate::font::{self, GLYPH_SIZE};
+
+pub const COLUMNS: u32 = 72;
+pub const ROWS: u32 = 40;
+
+const DISASM_BEFORE: usize = 5;
+const DISASM_LINES: usize = 16;
+const MEMORY_LINES: usize = 16;
+const BYTES_PER_LINE: u16 = 16;
+// Where the stack and call stack columns start on the disassembly lines
+const STACK_COLUMN: usize = 40;
+const CALLS_COLUMN: usize = 52;
+
+const TEXT_COLOR: [u8; 3] = [0xD0, 0xD0, 0xD0];
+const HIGHLIGHT_COLOR: [u8; 3] = [0xFF, 0xFF, 0x00];
+const BACKGROUND_COLOR: [u8; 3] = [0x10, 0x10, 0x28];
+
+// Text rendering of the CPU state: registers, flags, disassembly around PC, stack, call stack
+// and a memory view
+pub struct DebugView {
+    memory_start: u16,
+    pixels: Vec<u8>,
+}
+
+impl Default for DebugView {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugView {
+    pub fn new() -> Self {
+        DebugView {
+            memory_start: 0,
+            pixels: vec![0; (COLUMNS * ROWS * GLYPH_SIZE * GLYPH_SIZE * 3) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        COLUMNS * GLYPH_SIZE
+    }
+
+    pub fn height(&self) -> u32 {
+        ROWS * GLYPH_SIZE
+    }
+
+    pub fn pitch(&self) -> usize {
+        self.width() as usize * 3
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // Moves the memory view by whole lines
+    pub fn scroll(&mut self, lines: i32) {
+        let offset = (lines as i16 as u16).wrapping_mul(BYTES_PER_LINE);
+        self.memory_start = self.memory_start.wrapping_add(offset);
+    }
+
+    // Lines of text, the highlighted ones marked with `true`
+    fn lines(&self, cpu: &CPU, debug_info: &DebugInfo) -> Vec<(String, bool)> {
+        let bus = cpu.bus();
+        let mut lines = vec![
+            (
+                format!(
+                    "A={:02X}  X={:02X}  Y={:02X}  SP={:04X}  PC={:04X}  CYCLES={}",
+                    cpu.a(),
+                    cpu.x(),
+                    cpu.y(),
+                    cpu.sp(),
+                    cpu.pc(),
+                    cpu.cycles()
+                ),
+                false,
+            ),
+            (String::from("NV-BDIZC"), false),
+            (format!("{:08b}", cpu.p().pack()), false),
+            (String::new(), false),
+            (
+                format!(
+                    "{:<stack$}{:<calls$}CALLS",
+                    "DISASSEMBLY",
+                    "STACK",
+                    stack = STACK_COLUMN,
+                    calls = CALLS_COLUMN - STACK_COLUMN
+                ),
+                false,
+            ),
+        ];
+
+        // The innermost call first, under the current position
+        let calls = cpu
+            .call_stack()
+            .backtrace(*cpu.pc())
+            .into_iter()
+            .map(|level| match level.call {
+                Some(call) => format!("{} {}", call.kind, debug_info.describe(level.addr)),
+                None => debug_info.describe(level.addr),
+            })
+            .collect::<Vec<_>>();
+
+        let addrs = instructions_around(bus, *cpu.pc(), DISASM_BEFORE, DISASM_LINES);
+        for (i, addr) in addrs.into_iter().enumerate() {
+            let (text, len) = disassemble(bus, addr);
+            let bytes = (0..len)
+                .map(|offset| format!("{:02X}", bus.peek(addr.wrapping_add(offset))))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let marker = if addr == *cpu.pc() { '>' } else { ' ' };
+            let mut line = format!("{} {:04X}  {:<9} {}", marker, addr, bytes, text);
+
+            // The top of the stack first, up to the bottom of page 1
+            let stack_addr = cpu.sp().wrapping_add(1 + i as u16);
+            if stack_addr <= MemLayout::STACK_END {
+                line = format!(
+                    "{:<width$}{:04X}  {:02X}",
+                    line,
+                    stack_addr,
+                    bus.peek(stack_addr),
+                    width = STACK_COLUMN
+                );
+            }
+
+            if let Some(call) = calls.get(i) {
+                let call = call
+                    .chars()
+                    .take(COLUMNS as usize - CALLS_COLUMN)
+                    .collect::<String>();
+                line = format!("{:<width$}{}", line, call, width = CALLS_COLUMN);
+            }
+
+            lines.push((line, addr == *cpu.pc()));
+        }
+
+        lines.push((String::new(), false));
+        lines.push((
+            String::from("MEMORY (Up/Down, PageUp/PageDown to scroll)"),
+            false,
+        ));
+
+        for line_idx in 0..MEMORY_LINES as u16 {
+            let start = self
+                .memory_start
+                .wrapping_add(line_idx.wrapping_mul(BYTES_PER_LINE));
+            let bytes = (0..BYTES_PER_LINE)
+                .map(|offset| bus.peek(start.wrapping_add(offset)))
+                .collect::<Vec<_>>();
+
+            let hex = bytes
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let ascii = bytes
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7E => byte as char,
+                    _ => '.',
+                })
+                .collect::<String>();
+
+            lines.push((format!("{:04X}  {}  {}", start, hex, ascii), false));
+        }
+
+        lines
+    }
+
+    pub fn update(&mut self, cpu: &CPU, debug_info: &DebugInfo) {
+        let lines = self.lines(cpu, debug_info);
+        let width = self.width();
+        for row in 0..ROWS {
+            let (text, highlight) = lines
+                .get(row as usize)
+                .map_or((&[][..], false), |(text, highlight)| {
+                    (text.as_bytes(), *highlight)
+                });
+
+            let color = if highlight {
+                HIGHLIGHT_COLOR
+            } else {
+                TEXT_COLOR
+            };
+
+            for col in 0..COLUMNS {
+                let charcode = text.get(col as usize).copied().unwrap_or(b' ');
+                for y in 0..GLYPH_SIZE {
+                    for x in 0..GLYPH_SIZE {
+                        let rgb = if font::pixel(charcode, x, y) {
+                            color
+                        } else {
+                            BACKGROUND_COLOR
+                        };
+
+                        let i =
+                            (((row * GLYPH_SIZE + y) * width + col * GLYPH_SIZE + x) * 3) as usize;
+                        self.pixels[i..i + 3].copy_from_slice(&rgb);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+pub mod expression;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::bus::{Access, AccessKind};
+use crate::cpu::CPU;
+use crate::disasm::instruction_len;
+
+use self::expression::Expression;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Trigger {
+    Execute,
+    Read,
+    Write,
+    // Reads and writes
+    Access,
+}
+
+impl Trigger {
+    fn matches(self, event: Trigger) -> bool {
+        self == event || self == Trigger::Access && event != Trigger::Execute
+    }
+}
+
+// When a breakpoint breaks, by how many times its condition held so far
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HitCondition {
+    Equal(u32),
+    AtLeast(u32),
+    Multiple(u32),
+}
+
+impl HitCondition {
+    // `5` or `== 5` breaks on the 5th hit, `>= 5` on the 5th and later ones, `% 5` on every 5th
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let number = |count: &str| {
+            count
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid hit condition: {}", s))
+        };
+
+        if let Some(count) = s.strip_prefix(">=") {
+            Ok(HitCondition::AtLeast(number(count)?))
+        } else if let Some(count) = s.strip_prefix('>') {
+            Ok(HitCondition::AtLeast(number(count)?.saturating_add(1)))
+        } else if let Some(count) = s.strip_prefix('%') {
+            match number(count)? {
+                0 => Err(format!("Invalid hit condition: {}", s)),
+                count => Ok(HitCondition::Multiple(count)),
+            }
+        } else {
+            Ok(HitCondition::Equal(number(
+                s.strip_prefix("==").unwrap_or(s),
+            )?))
+        }
+    }
+
+    fn holds(self, hits: u32) -> bool {
+        match self {
+            HitCondition::Equal(count) => hits == count,
+            HitCondition::AtLeast(count) => hits >= count,
+            HitCondition::Multiple(count) => hits.is_multiple_of(count),
+        }
+    }
+}
+
+enum LogPart {
+    Text(String),
+    Value(Expression),
+}
+
+// A message with expressions in braces, e.g. `A = {A}`, formatted in hex
+pub struct LogMessage {
+    parts: Vec<LogPart>,
+}
+
+impl LogMessage {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed brace in log message: {}", s))?;
+
+            parts.push(LogPart::Text(rest[..start].to_string()));
+            parts.push(LogPart::Value(Expression::parse(
+                &rest[start + 1..start + end],
+            )?));
+            rest = &rest[start + end + 1..];
+        }
+
+        parts.push(LogPart::Text(rest.to_string()));
+        Ok(LogMessage { parts })
+    }
+
+    fn format(&self, cpu: &CPU) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                LogPart::Text(text) => text.clone(),
+                LogPart::Value(expr) => format!("${:02X}", expr.evaluate(cpu)),
+            })
+            .collect()
+    }
+}
+
+// Breaks when the CPU executes an instruction in, or reads or writes, an address range
+pub struct Breakpoint {
+    pub trigger: Trigger,
+    pub start: u16,
+    pub end: u16,
+    pub condition: Option<Expression>,
+    pub hit_condition: Option<HitCondition>,
+    // Logging breakpoints print the message and let the program continue
+    pub log: Option<LogMessage>,
+    hits: u32,
+}
+
+impl Breakpoint {
+    pub fn new(trigger: Trigger, start: u16, end: u16) -> Self {
+        Breakpoint {
+            trigger,
+            start,
+            end,
+            condition: None,
+            hit_condition: None,
+            log: None,
+            hits: 0,
+        }
+    }
+
+    pub fn at(addr: u16) -> Self {
+        Self::new(Trigger::Execute, addr, addr)
+    }
+
+    // How many times the breakpoint was reached with its condition holding
+    pub fn hits(&self) -> u32 {
+        self.hits
+    }
+
+    fn covers(&self, event: Trigger, addr: u16) -> bool {
+        self.trigger.matches(event) && (self.start..=self.end).contains(&addr)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BreakReason {
+    pub id: u32,
+    // The trigger of the breakpoint that was hit
+    pub trigger: Trigger,
+    // Execute, Read or Write
+    pub event: Trigger,
+    pub addr: u16,
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.trigger {
+            Trigger::Execute => "Breakpoint",
+            Trigger::Read => "Read watchpoint",
+            Trigger::Write => "Write watchpoint",
+            Trigger::Access => "Access watchpoint",
+        };
+        write!(f, "{} {}", kind, self.id)?;
+
+        // Access watchpoints also tell which kind of access triggered them
+        match (self.trigger, self.event) {
+            (Trigger::Access, Trigger::Read) => write!(f, " (read)")?,
+            (Trigger::Access, Trigger::Write) => write!(f, " (write)")?,
+            _ => {}
+        }
+
+        write!(f, " at ${:04X}", self.addr)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stop {
+    Break(BreakReason),
+    Halted,
+}
+
+// Breakpoints and watchpoints shared by the debugger frontends
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeMap<u32, Breakpoint>,
+    next_id: u32,
+    logs: Vec<String>,
+}
+
+impl Debugger {
+    pub fn add(&mut self, breakpoint: Breakpoint) -> u32 {
+        self.next_id += 1;
+        self.breakpoints.insert(self.next_id, breakpoint);
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Breakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    pub fn breakpoint(&self, id: u32) -> Option<&Breakpoint> {
+        self.breakpoints.get(&id)
+    }
+
+    // The first breakpoint with exactly this trigger and range
+    pub fn find(&self, trigger: Trigger, start: u16, end: u16) -> Option<u32> {
+        self.breakpoints
+            .iter()
+            .find(|(_, bp)| bp.trigger == trigger && bp.start == start && bp.end == end)
+            .map(|(id, _)| *id)
+    }
+
+    // Messages of the logging breakpoints reached since the last call
+    pub fn take_logs(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.logs)
+    }
+
+    // Counts a hit of every breakpoint covering one of the events of an instruction, once
+    // however many it covers, returning the first that breaks
+    fn hit(&mut self, cpu: &CPU, events: &[(Trigger, u16)]) -> Option<BreakReason> {
+        let mut reason = None;
+        for (id, bp) in self.breakpoints.iter_mut() {
+            let (event, addr) = match events.iter().find(|(event, addr)| bp.covers(*event, *addr)) {
+                Some(&event) => event,
+                None => continue,
+            };
+
+            if let Some(condition) = &bp.condition {
+                if condition.evaluate(cpu) == 0 {
+                    continue;
+                }
+            }
+
+            bp.hits += 1;
+            if let Some(hit_condition) = bp.hit_condition {
+                if !hit_condition.holds(bp.hits) {
+                    continue;
+                }
+            }
+
+            match &bp.log {
+                Some(message) => self.logs.push(message.format(cpu)),
+                None => {
+                    reason.get_or_insert(BreakReason {
+                        id: *id,
+                        trigger: bp.trigger,
+                        event,
+                        addr,
+                    });
+                }
+            }
+        }
+
+        reason
+    }
+
+    // Executes an instruction, then reports the watchpoint it triggered or the breakpoint
+    // at the next one. Fetching the instruction itself doesn't trigger read watchpoints.
+    pub fn step(&mut self, cpu: &mut CPU) -> Option<Stop> {
+        let watching = self
+            .breakpoints
+            .values()
+            .any(|bp| bp.trigger != Trigger::Execute);
+        cpu.bus_mut().set_access_tracking(watching);
+        // Drop the accesses made from outside the CPU since the last step, like memory
+        // written by a debugger
+        cpu.bus_mut().take_accesses();
+
+        let pc = *cpu.pc();
+        let len = instruction_len(cpu.bus(), pc).unwrap_or(1);
+        if !cpu.step() {
+            return Some(Stop::Halted);
+        }
+
+        // Word accesses and read-modify-write instructions access memory more than once
+        let events: Vec<_> = cpu
+            .bus_mut()
+            .take_accesses()
+            .into_iter()
+            .filter_map(|Access { addr, kind }| match kind {
+                AccessKind::Read if addr.wrapping_sub(pc) < len => None,
+                AccessKind::Read => Some((Trigger::Read, addr)),
+                AccessKind::Write => Some((Trigger::Write, addr)),
+            })
+            .collect();
+
+        let reason = self.hit(cpu, &events);
+        let hit = self.hit(cpu, &[(Trigger::Execute, *cpu.pc())]);
+        reason.or(hit).map(Stop::Break)
+    }
+}
@@ -0,0 +1,275 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::cpu::CPU;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Register {
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+    // A status flag, by its bit in P
+    Flag(u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token {
+    Number(u32),
+    Register(Register),
+    Op(Op),
+    Not,
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Number(u32),
+    Register(Register),
+    // The byte at an address
+    Memory(Box<Node>),
+    Not(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+}
+
+// An expression over registers, flags and memory, e.g. `A == $30 && X > 5` or `[$10] != 0`.
+// Comparisons and logical operators evaluate to 1 or 0.
+#[derive(Clone, Debug)]
+pub struct Expression {
+    root: Node,
+}
+
+fn register(name: &str) -> Option<Register> {
+    let register = match name.to_ascii_uppercase().as_str() {
+        "A" => Register::A,
+        "X" => Register::X,
+        "Y" => Register::Y,
+        "P" => Register::P,
+        "SP" => Register::SP,
+        "PC" => Register::PC,
+        "C" => Register::Flag(0),
+        "Z" => Register::Flag(1),
+        "I" => Register::Flag(2),
+        "D" => Register::Flag(3),
+        "B" => Register::Flag(4),
+        "V" => Register::Flag(6),
+        "N" => Register::Flag(7),
+        _ => return None,
+    };
+
+    Some(register)
+}
+
+fn number(chars: &mut Peekable<Chars>, radix: u32) -> Result<u32, String> {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()) {
+        digits.push(*c);
+        chars.next();
+    }
+
+    u32::from_str_radix(&digits, radix).map_err(|_| format!("Invalid number: {}", digits))
+}
+
+// Consumes the current character, and the next one if it is `next`
+fn two(chars: &mut Peekable<Chars>, next: char) -> bool {
+    chars.next();
+    chars.next_if_eq(&next).is_some()
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '$' => {
+                chars.next();
+                Token::Number(number(&mut chars, 16)?)
+            }
+            '%' => {
+                chars.next();
+                Token::Number(number(&mut chars, 2)?)
+            }
+            '0' if chars.clone().nth(1) == Some('x') => {
+                chars.nth(1);
+                Token::Number(number(&mut chars, 16)?)
+            }
+            _ if c.is_ascii_digit() => Token::Number(number(&mut chars, 10)?),
+            _ if c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric()) {
+                    name.push(c);
+                }
+
+                Token::Register(
+                    register(&name).ok_or_else(|| format!("Unknown register: {}", name))?,
+                )
+            }
+            '(' | ')' | '[' | ']' => {
+                chars.next();
+                match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '[' => Token::OpenBracket,
+                    _ => Token::CloseBracket,
+                }
+            }
+            '|' if two(&mut chars, '|') => Token::Op(Op::Or),
+            '&' if two(&mut chars, '&') => Token::Op(Op::And),
+            '=' if two(&mut chars, '=') => Token::Op(Op::Eq),
+            '!' if two(&mut chars, '=') => Token::Op(Op::Ne),
+            '!' => Token::Not,
+            '<' if two(&mut chars, '=') => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if two(&mut chars, '=') => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            _ => return Err(format!("Unexpected character: {}", c)),
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(String::from("Unbalanced brackets")),
+        }
+    }
+
+    // Operators of a precedence level, from the lowest
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        const LEVELS: [&[Op]; 3] = [
+            &[Op::Or],
+            &[Op::And],
+            &[Op::Eq, Op::Ne, Op::Lt, Op::Le, Op::Gt, Op::Ge],
+        ];
+
+        let ops = match LEVELS.get(level) {
+            Some(ops) => ops,
+            None => return self.unary(),
+        };
+
+        let mut node = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            if !ops.contains(op) {
+                break;
+            }
+
+            let op = *op;
+            self.pos += 1;
+            node = Node::Binary(op, Box::new(node), Box::new(self.binary(level + 1)?));
+        }
+
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Node::Number(value)),
+            Some(Token::Register(register)) => Ok(Node::Register(register)),
+            Some(Token::Not) => Ok(Node::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let node = self.binary(0)?;
+                self.expect(Token::Close)?;
+                Ok(node)
+            }
+            Some(Token::OpenBracket) => {
+                let node = self.binary(0)?;
+                self.expect(Token::CloseBracket)?;
+                Ok(Node::Memory(Box::new(node)))
+            }
+            _ => Err(String::from(
+                "Expected a number, register or memory address",
+            )),
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+
+        let root = parser.binary(0)?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!("Unexpected input in expression: {}", s));
+        }
+
+        Ok(Expression { root })
+    }
+
+    pub fn evaluate(&self, cpu: &CPU) -> u32 {
+        evaluate(&self.root, cpu)
+    }
+}
+
+fn evaluate(node: &Node, cpu: &CPU) -> u32 {
+    match node {
+        Node::Number(value) => *value,
+        Node::Register(register) => match register {
+            Register::A => *cpu.a() as u32,
+            Register::X => *cpu.x() as u32,
+            Register::Y => *cpu.y() as u32,
+            Register::P => cpu.p().pack() as u32,
+            Register::SP => *cpu.sp() as u32,
+            Register::PC => *cpu.pc() as u32,
+            Register::Flag(bit) => (cpu.p().pack() >> bit) as u32 & 1,
+        },
+        Node::Memory(addr) => cpu.bus().peek(evaluate(addr, cpu) as u16) as u32,
+        Node::Not(node) => (evaluate(node, cpu) == 0) as u32,
+        Node::Binary(op, lhs, rhs) => {
+            let lhs = evaluate(lhs, cpu);
+            // Short-circuiting doesn't matter without side effects
+            let rhs = evaluate(rhs, cpu);
+            let result = match op {
+                Op::Or => lhs != 0 || rhs != 0,
+                Op::And => lhs != 0 && rhs != 0,
+                Op::Eq => lhs == rhs,
+                Op::Ne => lhs != rhs,
+                Op::Lt => lhs < rhs,
+                Op::Le => lhs <= rhs,
+                Op::Gt => lhs > rhs,
+                Op::Ge => lhs >= rhs,
+            };
+
+            result as u32
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::bus::Bus;
+use crate::opcodes::{AddressingMode, OPCODES_MAP};
+
+// Returns the instruction at `addr` in 6502 assembler syntax, and its length in bytes
+pub fn disassemble(bus: &Bus, addr: u16) -> (String, u16) {
+    let code = bus.peek(addr);
+    let opcode = match OPCODES_MAP.get(&code) {
+        Some(opcode) => opcode,
+        None => return (format!(".byte ${:02X}", code), 1),
+    };
+
+    let byte = bus.peek(addr.wrapping_add(1));
+    let word = u16::from_le_bytes([byte, bus.peek(addr.wrapping_add(2))]);
+    let operand = match opcode.mode {
+        AddressingMode::Immediate => format!(" #${:02X}", byte),
+        AddressingMode::ZeroPage => format!(" ${:02X}", byte),
+        AddressingMode::ZeroPage_X => format!(" ${:02X},X", byte),
+        AddressingMode::ZeroPage_Y => format!(" ${:02X},Y", byte),
+        AddressingMode::Absolute => format!(" ${:04X}", word),
+        AddressingMode::Absolute_X => format!(" ${:04X},X", word),
+        AddressingMode::Absolute_Y => format!(" ${:04X},Y", word),
+        AddressingMode::Indirect => format!(" (${:04X})", word),
+        AddressingMode::Indirect_X => format!(" (${:02X},X)", byte),
+        AddressingMode::Indirect_Y => format!(" (${:02X}),Y", byte),
+        // Branches are the only two-byte instructions without an addressing mode
+        AddressingMode::NoneAddressing if opcode.len == 2 => {
+            let target = addr.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!(" ${:04X}", target)
+        }
+        AddressingMode::NoneAddressing => String::new(),
+    };
+
+    (format!("{}{}", opcode.mnemonic, operand), opcode.len as u16)
+}
+
+// Length in bytes of the instruction at `addr`, if its opcode is valid
+pub fn instruction_len(bus: &Bus, addr: u16) -> Option<u16> {
+    OPCODES_MAP
+        .get(&bus.peek(addr))
+        .map(|opcode| opcode.len as u16)
+}
+
+// Addresses of up to `before` instructions preceding `pc`, `pc` itself and the ones following it,
+// `count` in total. Code can't be decoded backwards reliably, so the preceding instructions
+// are found by looking for an earlier address whose valid instructions line up with `pc`.
+pub fn instructions_around(bus: &Bus, pc: u16, before: usize, count: usize) -> Vec<u16> {
+    let max_distance = before as u16 * 3;
+    let mut addrs = (1..=max_distance)
+        .rev()
+        .find_map(|distance| {
+            let mut addrs = vec![];
+            let mut addr = pc.wrapping_sub(distance);
+            while pc.wrapping_sub(addr) != 0 {
+                if pc.wrapping_sub(addr) > max_distance {
+                    return None;
+                }
+
+                addrs.push(addr);
+                addr = addr.wrapping_add(instruction_len(bus, addr)?);
+            }
+
+            Some(addrs)
+        })
+        .unwrap_or_default();
+
+    addrs.drain(..addrs.len().saturating_sub(before));
+
+    let mut addr = pc;
+    while addrs.len() < count {
+        addrs.push(addr);
+        addr = addr.wrapping_add(instruction_len(bus, addr).unwrap_or(1));
+    }
+
+    addrs
+}
@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::{RAMAccess, CPU};
+use crate::debugger::{BreakReason, Breakpoint, Debugger, Stop, Trigger};
+
+// Check for a Ctrl+C from GDB once per this many instructions while continuing
+const INTERRUPT_CHECK_INTERVAL: u32 = 4096;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.yanes.cpu">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8" regnum="1"/>
+    <reg name="y" bitsize="8" type="uint8" regnum="2"/>
+    <reg name="p" bitsize="8" type="uint8" regnum="3"/>
+    <reg name="sp" bitsize="16" type="data_ptr" regnum="4"/>
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="5"/>
+  </feature>
+</target>
+"#;
+
+// Register numbers, in the order of the `g` packet
+const REG_A: usize = 0;
+const REG_X: usize = 1;
+const REG_Y: usize = 2;
+const REG_P: usize = 3;
+const REG_SP: usize = 4;
+const REG_PC: usize = 5;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+// `addr,len` of the memory packets
+fn range(s: &str) -> Option<(u16, u16)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((number(addr)?, number(len)?))
+}
+
+// `type,addr,kind` of the breakpoint packets, as the breakpoint's trigger and address range
+fn breakpoint_range(s: &str) -> Option<(Trigger, u16, u16)> {
+    let (kind, range_spec) = s.split_once(',')?;
+    let (addr, len) = range(range_spec)?;
+    let trigger = match kind {
+        // Software and hardware breakpoints are both kept outside of guest memory
+        "0" | "1" => return Some((Trigger::Execute, addr, addr)),
+        "2" => Trigger::Write,
+        "3" => Trigger::Read,
+        "4" => Trigger::Access,
+        _ => return None,
+    };
+
+    Some((trigger, addr, addr.wrapping_add(len.max(1) - 1)))
+}
+
+fn read_register(cpu: &CPU, reg: usize) -> Option<Vec<u8>> {
+    match reg {
+        REG_A => Some(vec![*cpu.a()]),
+        REG_X => Some(vec![*cpu.x()]),
+        REG_Y => Some(vec![*cpu.y()]),
+        REG_P => Some(vec![cpu.p().pack()]),
+        REG_SP => Some(cpu.sp().to_le_bytes().to_vec()),
+        REG_PC => Some(cpu.pc().to_le_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+fn write_register(cpu: &mut CPU, reg: usize, bytes: &[u8]) -> Option<()> {
+    let word = || Some(u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]));
+    match reg {
+        REG_A => cpu.set_a(*bytes.first()?),
+        REG_X => cpu.set_x(*bytes.first()?),
+        REG_Y => cpu.set_y(*bytes.first()?),
+        REG_P => cpu.set_p(*bytes.first()?),
+        REG_SP => cpu.set_sp(word()?),
+        REG_PC => cpu.set_pc(word()?),
+        _ => return None,
+    }
+
+    Some(())
+}
+
+enum Action {
+    Reply(String),
+    Kill,
+    Detach,
+}
+
+// A GDB Remote Serial Protocol connection controlling the CPU
+pub struct GdbStub {
+    stream: TcpStream,
+    // Bytes which arrived while the program ran, for the next packet
+    pending: VecDeque<u8>,
+    debugger: Debugger,
+    no_ack: bool,
+    exited: bool,
+}
+
+// Waits for GDB to connect, then runs the program under its control until it detaches or kills it
+pub fn serve(cpu: &mut CPU, listener: TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbStub::new(stream).run(cpu)
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> Self {
+        GdbStub {
+            stream,
+            pending: VecDeque::new(),
+            debugger: Debugger::default(),
+            no_ack: false,
+            exited: false,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Returns None once GDB has disconnected
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Acks and Ctrl+C while stopped carry no meaning
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                *digit = self.read_byte()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            }
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            let valid = expected == Some(data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)));
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+
+    // Whether GDB sent a Ctrl+C since the last check, without blocking. Other bytes are kept
+    // for the packet reader, and a disconnect stops the program with an error.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0; 64];
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "GDB disconnected while the program was running",
+            )),
+            Ok(len) => {
+                let interrupted = buf[..len].contains(&0x03);
+                self.pending
+                    .extend(buf[..len].iter().filter(|byte| **byte != 0x03));
+                Ok(interrupted)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn exit_reply(&self, cpu: &CPU) -> String {
+        format!("W{:02x}", cpu.exit_code().unwrap_or(0))
+    }
+
+    // Watchpoints tell GDB which address was accessed
+    fn break_reply(&self, reason: BreakReason) -> String {
+        let kind = match reason.trigger {
+            Trigger::Write => "watch",
+            Trigger::Read => "rwatch",
+            Trigger::Access => "awatch",
+            Trigger::Execute => return String::from("S05"),
+        };
+
+        format!("T05{}:{:04x};", kind, reason.addr)
+    }
+
+    fn resume(&mut self, cpu: &mut CPU, single_step: bool) -> io::Result<String> {
+        if self.exited {
+            return Ok(self.exit_reply(cpu));
+        }
+
+        let mut count = 0u32;
+        loop {
+            let stop = self.debugger.step(cpu);
+            let mut messages = self.debugger.take_logs();
+            messages.extend(cpu.call_stack_mut().take_warnings());
+            for message in messages {
+                self.send(&format!("O{}", hex(format!("{}\n", message).as_bytes())))?;
+            }
+
+            match stop {
+                Some(Stop::Halted) => {
+                    self.exited = true;
+                    return Ok(self.exit_reply(cpu));
+                }
+                Some(Stop::Break(reason)) => return Ok(self.break_reply(reason)),
+                None if single_step => return Ok(String::from("S05")),
+                None => {}
+            }
+
+            count = count.wrapping_add(1);
+            if count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.interrupted()? {
+                return Ok(String::from("S02"));
+            }
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> io::Result<Action> {
+        let error = || String::from("E01");
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => String::from("S05"),
+            Some(b'c') => self.resume(cpu, false)?,
+            Some(b's') => self.resume(cpu, true)?,
+            Some(b'k') => return Ok(Action::Kill),
+            Some(b'D') => return Ok(Action::Detach),
+            Some(b'H') => String::from("OK"),
+
+            Some(b'g') => (REG_A..=REG_PC)
+                .filter_map(|reg| read_register(cpu, reg))
+                .map(|bytes| hex(&bytes))
+                .collect(),
+
+            Some(b'G') => match unhex(&packet[1..]) {
+                Some(bytes) if bytes.len() == 8 => {
+                    let fields = [
+                        &bytes[0..1],
+                        &bytes[1..2],
+                        &bytes[2..3],
+                        &bytes[3..4],
+                        &bytes[4..6],
+                        &bytes[6..8],
+                    ];
+                    for (reg, field) in fields.iter().enumerate() {
+                        write_register(cpu, reg, field);
+                    }
+
+                    String::from("OK")
+                }
+                _ => error(),
+            },
+
+            Some(b'p') => usize::from_str_radix(&packet[1..], 16)
+                .ok()
+                .and_then(|reg| read_register(cpu, reg))
+                .map_or_else(error, |bytes| hex(&bytes)),
+
+            Some(b'P') => packet[1..]
+                .split_once('=')
+                .and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    write_register(cpu, reg, &unhex(value)?)
+                })
+                .map_or_else(error, |_| String::from("OK")),
+
+            Some(b'm') => range(&packet[1..]).map_or_else(error, |(addr, len)| {
+                let bytes = (0..len)
+                    .map(|offset| cpu.bus().peek(addr.wrapping_add(offset)))
+                    .collect::<Vec<_>>();
+
+                hex(&bytes)
+            }),
+
+            Some(b'M') => packet[1..]
+                .split_once(':')
+                .and_then(|(range_spec, data)| {
+                    let (addr, len) = range(range_spec)?;
+                    let bytes = unhex(data)?;
+                    if bytes.len() != len as usize {
+                        return None;
+                    }
+
+                    for (offset, byte) in bytes.into_iter().enumerate() {
+                        cpu.mem_write_byte(addr.wrapping_add(offset as u16), byte);
+                    }
+
+                    Some(())
+                })
+                .map_or_else(error, |_| String::from("OK")),
+
+            Some(b'Z') | Some(b'z') => match breakpoint_range(&packet[1..]) {
+                Some((trigger, start, end)) => {
+                    if packet.starts_with('Z') {
+                        self.debugger.add(Breakpoint::new(trigger, start, end));
+                    } else if let Some(id) = self.debugger.find(trigger, start, end) {
+                        self.debugger.remove(id);
+                    }
+
+                    String::from("OK")
+                }
+                // An empty reply tells GDB the breakpoint type is not supported
+                None => String::new(),
+            },
+
+            _ => self.query(packet),
+        };
+
+        Ok(Action::Reply(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return String::from("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+");
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match range(args) {
+                Some((offset, len)) => {
+                    let start = (offset as usize).min(TARGET_XML.len());
+                    let end = (start + len as usize).min(TARGET_XML.len());
+                    let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &TARGET_XML[start..end])
+                }
+                None => String::from("E01"),
+            };
+        }
+
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                String::from("OK")
+            }
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            // An empty reply tells GDB the packet is not supported
+            _ => String::new(),
+        }
+    }
+
+    pub fn run(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(cpu, &packet)? {
+                Action::Reply(reply) => self.send(&reply)?,
+                Action::Kill => return Ok(()),
+                Action::Detach => {
+                    self.send("OK")?;
+                    while !self.exited && cpu.step() {}
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+// Character typed with Shift held on a US keyboard layout
+pub fn char_to_shift_mod(charcode: u8) -> u8 {
+    match charcode {
+        0x60 => 0x7E,
+        0x30 => 0x29,
+        0x31 => 0x21,
+        0x32 => 0x40,
+        0x33 => 0x23,
+        0x34 => 0x24,
+        0x35 => 0x25,
+        0x36 => 0x5E,
+        0x37 => 0x26,
+        0x38 => 0x2A,
+        0x39 => 0x28,
+        0x2D => 0x5F,
+        0x3D => 0x2B,
+        0x27 => 0x22,
+        0x3B => 0x3A,
+        0x2F => 0x3F,
+        0x2E => 0x3E,
+        0x2C => 0x3C,
+        0x5B => 0x7B,
+        0x5D => 0x7D,
+        0x5C => 0x7C,
+        0x61..=0x7A => charcode ^ 0x20,
+        _ => charcode,
+    }
+}
+
+// Byte written to MemLayout::KEYCODE_ADDR for a key press, shared by the frontends
+pub fn keycode(charcode: u8, shift: bool) -> u8 {
+    if shift {
+        char_to_shift_mod(charcode)
+    } else {
+        charcode
+    }
+}
@@ -1,147 +1,680 @@
 pub mod bus;
+pub mod call_stack;
+pub mod capture;
 pub mod cpu;
+pub mod dap;
+pub mod debug_info;
+pub mod debug_view;
+pub mod debugger;
+pub mod disasm;
+pub mod font;
+pub mod gdb;
+pub mod keyboard;
 pub mod opcodes;
+pub mod options;
+pub mod screen;
 pub mod status_register;
+pub mod syscall;
+pub mod tui;
 
 use std::error::Error;
-use std::{env, fs};
+use std::io::{self, BufWriter};
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
 
 use match_all::match_all;
 
-use sdl2::event::Event;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::WindowCanvas;
+use sdl2::video::FullscreenType;
 use sdl2::EventPump;
 
-use crate::cpu::{MemLayout, RAMAccess, CPU};
+use crate::bus::apu::SAMPLE_RATE;
+use crate::bus::display::CYCLES_PER_FRAME;
+use crate::capture::{save_png, unused_path, Recorder};
+use crate::cpu::{MemLayout, RAMAccess, CLOCK_HZ, CPU};
+use crate::debug_info::DebugInfo;
+use crate::debug_view::DebugView;
+use crate::options::{ClockSpeed, Filter, Options};
+use crate::screen::Frame;
+use crate::tui::Terminal;
 
 #[cfg(test)]
 mod test;
 
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => Color::BLACK,
-        1 => Color::WHITE,
-        2 | 9 => Color::GREY,
-        3 | 10 => Color::RED,
-        4 | 11 => Color::GREEN,
-        5 | 12 => Color::BLUE,
-        6 | 13 => Color::MAGENTA,
-        7 | 14 => Color::YELLOW,
-        _ => Color::CYAN,
-    }
-}
-
-fn char_to_shift_mod(charcode: u8) -> u8 {
-    match charcode {
-        0x60 => 0x7E,
-        0x30 => 0x29,
-        0x31 => 0x21,
-        0x32 => 0x40,
-        0x33 => 0x23,
-        0x34 => 0x24,
-        0x35 => 0x25,
-        0x36 => 0x5E,
-        0x37 => 0x26,
-        0x38 => 0x2A,
-        0x39 => 0x28,
-        0x2D => 0x5F,
-        0x3D => 0x2B,
-        0x27 => 0x22,
-        0x3B => 0x3A,
-        0x2F => 0x3F,
-        0x2E => 0x3E,
-        0x2C => 0x3C,
-        0x5B => 0x7B,
-        0x5D => 0x7D,
-        0x5C => 0x7C,
-        0x61..=0x7A => charcode ^ 0x20,
-        _ => charcode,
-    }
-}
-
-fn update_screen_state(cpu: &CPU, frame: &mut [u8; 3072]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
-    for i in MemLayout::VGA_BUF_START..=MemLayout::VGA_BUF_END {
-        let color_idx = cpu.mem_read_byte(i as u16);
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
-            update = true;
-        }
-        frame_idx += 3;
-    }
-    update
-}
-
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+type WavOutput = hound::WavWriter<BufWriter<fs::File>>;
+
+// Lines moved by PageUp and PageDown in the debugger memory view
+const DEBUG_PAGE_LINES: u32 = 16;
+// Don't try to catch up after falling this many frames behind
+const MAX_LAG_FRAMES: u32 = 4;
+// Keep at most a quarter of a second of 16-bit samples waiting for playback
+const MAX_QUEUED_AUDIO: u32 = SAMPLE_RATE / 4 * 2;
+
+// Requests from the user to the frontend itself, rather than to the program
+#[derive(Default)]
+struct FrontendActions {
+    quit: bool,
+    redraw: bool,
+    screenshot: bool,
+    toggle_recording: bool,
+    toggle_pause: bool,
+    advance_frame: bool,
+    step: bool,
+    soft_reset: bool,
+    hard_reset: bool,
+    toggle_debugger: bool,
+    // Lines to move the debugger memory view by
+    debug_scroll: i32,
+}
+
+// Keys pressed in the debugger window are not passed to the program
+fn handle_user_input(
+    cpu: &mut CPU,
+    event_pump: &mut EventPump,
+    canvas: &mut WindowCanvas,
+    debug_window: Option<u32>,
+) -> FrontendActions {
+    let mut actions = FrontendActions::default();
     for event in event_pump.poll_iter() {
         match_all! { event,
-            Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                if !*cpu.p().I() {
-                    cpu.mem_write_word(MemLayout::IRQ_VECTOR, 0);
-                    cpu.mem_write_byte(*cpu.pc(), 0);
+            Event::Window { win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed, .. } => {
+                actions.redraw = true;
+            },
+            Event::Window { win_event: WindowEvent::Close, window_id, .. } => {
+                if Some(window_id) == debug_window {
+                    actions.toggle_debugger = true;
+                } else {
+                    actions.quit = true;
                 }
             },
-            Event::KeyDown { keycode: Some(keycode), keymod, .. } => {
-                if let 0x00..=0x7f = keycode as i32 {
-                    match keymod {
-                        Mod::LSHIFTMOD | Mod::RSHIFTMOD => {
-                            cpu.mem_write_byte(MemLayout::KEYCODE_ADDR, char_to_shift_mod(keycode as u8));
-                        },
-                        _ => {
-                            cpu.mem_write_byte(MemLayout::KEYCODE_ADDR, keycode as u8);
-                        }
-                    }
+            Event::KeyDown { keycode: Some(Keycode::F1), repeat: false, .. } => {
+                actions.toggle_debugger = true;
+            },
+            Event::KeyDown { keycode: Some(keycode), window_id, .. } => {
+                if Some(window_id) == debug_window {
+                    actions.debug_scroll += match keycode {
+                        Keycode::Up => -1,
+                        Keycode::Down => 1,
+                        Keycode::PageUp => -(DEBUG_PAGE_LINES as i32),
+                        Keycode::PageDown => DEBUG_PAGE_LINES as i32,
+                        _ => 0,
+                    };
+                }
+            },
+            Event::KeyDown { keycode: Some(Keycode::F12), repeat: false, .. } => {
+                actions.screenshot = true;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F10), repeat: false, .. } => {
+                actions.toggle_recording = true;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F5 | Keycode::Pause), repeat: false, .. } => {
+                actions.toggle_pause = true;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F6), .. } => {
+                actions.advance_frame = true;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F7), .. } => {
+                actions.step = true;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F8), repeat: false, .. } => {
+                actions.soft_reset = true;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => {
+                actions.hard_reset = true;
+            },
+            Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => {
+                let window = canvas.window_mut();
+                let fullscreen = match window.fullscreen_state() {
+                    FullscreenType::Off => FullscreenType::Desktop,
+                    _ => FullscreenType::Off,
+                };
+
+                window.set_fullscreen(fullscreen).unwrap();
+                actions.redraw = true;
+            },
+            Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                actions.quit = true;
+            },
+            Event::KeyDown { keycode: Some(keycode), keymod, window_id, .. } => {
+                if Some(window_id) != debug_window && (0x00..=0x7f).contains(&(keycode as i32)) {
+                    let shift = matches!(keymod, Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    cpu.mem_write_byte(MemLayout::KEYCODE_ADDR, keyboard::keycode(keycode as u8, shift));
                 }
             }
         }
     }
+
+    actions
 }
 
-fn main() -> Result<(), Box<dyn Error + 'static>> {
-    let args = env::args().collect::<Vec<_>>();
-    if args.len() < 2 {
-        println!("Usage: {} program.bin", &args[0]);
-        return Ok(());
+// Initial window size for a screen resolution, 320 pixels wide unless the scale is given
+fn window_size(frame: &Frame, scale: Option<u32>) -> (u32, u32) {
+    let scale = scale.unwrap_or_else(|| (320 / frame.width()).max(2));
+    (frame.width() * scale, frame.height() * scale)
+}
+
+fn drain_audio(cpu: &mut CPU, queue: Option<&AudioQueue<i16>>, wav: &mut Option<WavOutput>) {
+    if cpu.bus().apu().pending_samples() == 0 {
+        return;
+    }
+
+    let samples = cpu.bus_mut().apu_mut().take_samples();
+    if let Some(queue) = queue {
+        if queue.size() < MAX_QUEUED_AUDIO {
+            queue.queue(&samples);
+        }
+    }
+
+    if let Some(wav) = wav {
+        for sample in samples {
+            wav.write_sample(sample).unwrap();
+        }
+    }
+}
+
+fn report_stack_warnings(cpu: &mut CPU) {
+    for warning in cpu.call_stack_mut().take_warnings() {
+        eprintln!("Warning: {}", warning);
     }
+}
 
-    let bytes = fs::read(&args[1])?;
+// Shows where a crashed program was, following the calls and interrupts in progress
+fn print_backtrace(cpu: &CPU, debug_info: &DebugInfo) {
+    eprintln!("Backtrace:");
+    for (idx, level) in cpu.call_stack().backtrace(*cpu.pc()).iter().enumerate() {
+        let mut line = format!("#{:<3} ${:04X}", idx, level.addr);
+        if debug_info.symbol(level.addr).is_some() {
+            line += &format!(" in {}", debug_info.describe(level.addr));
+        }
 
+        if let Some(call) = level.call {
+            line += &format!(
+                ", {} to ${:04X} returning to ${:04X}",
+                call.kind, call.entry, call.return_addr
+            );
+        }
+
+        eprintln!("{}", line);
+    }
+}
+
+// Runs the program until the next vertical blank. Returns false once it has halted.
+fn run_frame(cpu: &mut CPU, queue: Option<&AudioQueue<i16>>, wav: &mut Option<WavOutput>) -> bool {
+    while cpu.step() {
+        if cpu.bus_mut().display_mut().take_frame_ready() {
+            drain_audio(cpu, queue, wav);
+            return true;
+        }
+    }
+
+    false
+}
+
+// Sleeps after every emulated frame so the program runs at the requested clock speed
+struct FramePacer {
+    frame_time: Option<Duration>,
+    deadline: Instant,
+}
+
+impl FramePacer {
+    fn new(speed: ClockSpeed) -> Self {
+        let frame_time = match speed {
+            ClockSpeed::Hz(hz) => Some(Duration::from_nanos(CYCLES_PER_FRAME * 1_000_000_000 / hz)),
+            ClockSpeed::Unthrottled => None,
+        };
+
+        FramePacer {
+            frame_time,
+            deadline: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self) {
+        if let Some(delay) = self.delay(Instant::now()) {
+            thread::sleep(delay);
+        }
+    }
+
+    // How long to sleep at `now` after another frame, if at all
+    fn delay(&mut self, now: Instant) -> Option<Duration> {
+        let frame_time = self.frame_time?;
+        self.deadline += frame_time;
+        if self.deadline > now {
+            Some(self.deadline - now)
+        } else {
+            // Give up catching up after a long stall, instead of running flat out
+            if now - self.deadline > frame_time * MAX_LAG_FRAMES {
+                self.deadline = now;
+            }
+
+            None
+        }
+    }
+}
+
+fn record_frame(recorder: &mut Option<Recorder>, frame: &Frame, changed: bool) {
+    if let Some(rec) = recorder {
+        if let Err(e) = rec.record(frame, changed) {
+            eprintln!("Recording into {} failed: {}", rec.path().display(), e);
+            *recorder = None;
+        }
+    }
+}
+
+fn toggle_recording(recorder: &mut Option<Recorder>, scale: u32) {
+    match recorder.take() {
+        Some(rec) => {
+            let path = rec.path().to_path_buf();
+            match rec.finish() {
+                Ok(()) => eprintln!("Saved {}", path.display()),
+                Err(e) => eprintln!("Recording into {} failed: {}", path.display(), e),
+            }
+        }
+        None => *recorder = Some(Recorder::new(&unused_path("yanes", "gif"), scale)),
+    }
+}
+
+// Two bars in the top right corner of the screen
+fn draw_pause_indicator(canvas: &mut WindowCanvas, frame: &Frame) {
+    let unit = (frame.width() / 32).max(1);
+    let (x, y) = ((frame.width() - unit * 6) as i32, unit as i32);
+
+    canvas.set_draw_color(Color::BLACK);
+    canvas
+        .fill_rect(Rect::new(x - unit as i32, 0, unit * 7, unit * 7))
+        .unwrap();
+
+    canvas.set_draw_color(Color::WHITE);
+    for bar_x in [x, x + unit as i32 * 3].iter() {
+        canvas
+            .fill_rect(Rect::new(*bar_x, y, unit * 2, unit * 5))
+            .unwrap();
+    }
+
+    canvas.set_draw_color(Color::BLACK);
+}
+
+fn boot(cpu: &mut CPU, program: &[u8], options: &Options) -> Result<(), String> {
+    cpu.load(program.to_vec());
+    cpu.load_args(&options.args, &options.env)
+}
+
+fn run_sdl(
+    cpu: &mut CPU,
+    program: &[u8],
+    wav: &mut Option<WavOutput>,
+    recorder: &mut Option<Recorder>,
+    options: &Options,
+    debug_info: &DebugInfo,
+) {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("Yanes", 320u32, 320u32)
-        .position_centered()
+    let audio_queue = match options.clock {
+        // Unthrottled, sound would be produced faster than it can be played
+        Some(ClockSpeed::Unthrottled) => None,
+        _ => sdl_context
+            .audio()
+            .and_then(|audio| {
+                let spec = AudioSpecDesired {
+                    freq: Some(SAMPLE_RATE as i32),
+                    channels: Some(1),
+                    samples: None,
+                };
+
+                audio.open_queue::<i16, _>(None, &spec)
+            })
+            .map_err(|e| eprintln!("Audio is unavailable: {}", e))
+            .ok(),
+    };
+
+    if let Some(queue) = &audio_queue {
+        queue.resume();
+    }
+
+    cpu.bus_mut()
+        .apu_mut()
+        .set_enabled(audio_queue.is_some() || wav.is_some());
+
+    // Must be set before any texture is created
+    sdl2::hint::set(
+        "SDL_RENDER_SCALE_QUALITY",
+        match options.filter {
+            Filter::Nearest => "nearest",
+            Filter::Linear => "linear",
+        },
+    );
+
+    let mut frame = Frame::new();
+    let (width, height) = window_size(&frame, options.scale);
+    let mut window_builder = video_subsystem.window("Yanes", width, height);
+    window_builder.position_centered().resizable();
+    if options.fullscreen {
+        window_builder.fullscreen_desktop();
+    }
+
+    let mut canvas = window_builder
+        .build()
+        .unwrap()
+        .into_canvas()
         .build()
         .unwrap();
+    canvas.set_draw_color(Color::BLACK);
+    canvas
+        .set_logical_size(frame.width(), frame.height())
+        .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
 
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
+        .create_texture_target(PixelFormatEnum::RGB24, frame.width(), frame.height())
         .unwrap();
 
-    let mut cpu = CPU::new();
-    cpu.load(bytes);
+    let mut pacer = FramePacer::new(options.clock.unwrap_or(ClockSpeed::Hz(CLOCK_HZ)));
+    let mut debug_view = DebugView::new();
 
-    let mut screen_state = [0u8; 3072];
+    // The debugger window stays hidden until F1 shows it
+    let window = video_subsystem
+        .window(
+            "Yanes debugger",
+            debug_view.width() * 2,
+            debug_view.height() * 2,
+        )
+        .resizable()
+        .hidden()
+        .build()
+        .unwrap();
+
+    let mut debug_canvas = window.into_canvas().build().unwrap();
+    debug_canvas
+        .set_logical_size(debug_view.width(), debug_view.height())
+        .unwrap();
+
+    let debug_creator = debug_canvas.texture_creator();
+    let mut debug_texture = debug_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            debug_view.width(),
+            debug_view.height(),
+        )
+        .unwrap();
+    let mut debugging = false;
+
+    let mut paused = false;
+    loop {
+        let debug_window = debugging.then(|| debug_canvas.window().id());
+        let actions = handle_user_input(cpu, &mut event_pump, &mut canvas, debug_window);
+        if actions.quit {
+            break;
+        }
+
+        if actions.toggle_debugger {
+            debugging = !debugging;
+            if debugging {
+                debug_canvas.window_mut().show();
+            } else {
+                debug_canvas.window_mut().hide();
+            }
+        }
+
+        debug_view.scroll(actions.debug_scroll);
 
-    cpu.run_with_callback(move |cpu| {
-        handle_user_input(cpu, &mut event_pump);
-        if update_screen_state(cpu, &mut screen_state) {
-            texture.update(None, &screen_state, 96).unwrap();
+        if actions.toggle_pause {
+            paused = !paused;
+            let title = if paused { "Yanes [paused]" } else { "Yanes" };
+            canvas.window_mut().set_title(title).unwrap();
+        }
+
+        if actions.soft_reset {
+            cpu.soft_reset();
+        }
+
+        if actions.hard_reset {
+            cpu.reset();
+            // The arguments fit at startup, so they still do
+            boot(cpu, program, options).unwrap();
+        }
+
+        let running = if !paused || actions.advance_frame {
+            run_frame(cpu, audio_queue.as_ref(), wav)
+        } else if actions.step {
+            let running = cpu.step();
+            // A vertical blank reached by the step would otherwise end the next frame at once
+            cpu.bus_mut().display_mut().take_frame_ready();
+            running
+        } else {
+            true
+        };
+        report_stack_warnings(cpu);
+
+        if !running {
+            break;
+        }
+
+        let updated = frame.update(cpu.bus_mut());
+        record_frame(recorder, &frame, updated);
+
+        if actions.screenshot {
+            let path = unused_path("yanes", "png");
+            match save_png(&frame, options.capture_scale, &path) {
+                Ok(()) => eprintln!("Saved {}", path.display()),
+                Err(e) => eprintln!("Unable to save {}: {}", path.display(), e),
+            }
+        }
+
+        if actions.toggle_recording {
+            toggle_recording(recorder, options.capture_scale);
+        }
+
+        if updated {
+            if texture.query().width != frame.width() || texture.query().height != frame.height() {
+                if canvas.window().fullscreen_state() == FullscreenType::Off {
+                    let (width, height) = window_size(&frame, options.scale);
+                    canvas.window_mut().set_size(width, height).unwrap();
+                }
+
+                // The logical size keeps the aspect ratio, with black bars around the screen
+                canvas
+                    .set_logical_size(frame.width(), frame.height())
+                    .unwrap();
+                texture = creator
+                    .create_texture_target(PixelFormatEnum::RGB24, frame.width(), frame.height())
+                    .unwrap();
+            }
+
+            texture.update(None, frame.pixels(), frame.pitch()).unwrap();
+        }
+
+        if updated || actions.redraw || paused {
+            canvas.clear();
             canvas.copy(&texture, None, None).unwrap();
+            if paused {
+                draw_pause_indicator(&mut canvas, &frame);
+            }
+
             canvas.present();
         }
-    });
 
-    println!("{:?}", cpu);
+        if debugging {
+            debug_view.update(cpu, debug_info);
+
+            debug_texture
+                .update(None, debug_view.pixels(), debug_view.pitch())
+                .unwrap();
+            debug_canvas.clear();
+            debug_canvas.copy(&debug_texture, None, None).unwrap();
+            debug_canvas.present();
+        }
+
+        pacer.wait();
+    }
+}
+
+fn run_tui(
+    cpu: &mut CPU,
+    wav: &mut Option<WavOutput>,
+    recorder: &mut Option<Recorder>,
+    options: &Options,
+) -> io::Result<()> {
+    cpu.bus_mut().apu_mut().set_enabled(wav.is_some());
+
+    let mut terminal = Terminal::new()?;
+    let mut frame = Frame::new();
+    let mut pacer = FramePacer::new(options.clock.unwrap_or(ClockSpeed::Hz(CLOCK_HZ)));
+    while terminal.handle_input(cpu)? && run_frame(cpu, None, wav) {
+        let changed = frame.update(cpu.bus_mut());
+        record_frame(recorder, &frame, changed);
+        if changed {
+            terminal.draw(&frame)?;
+        }
+
+        pacer.wait();
+    }
+
     Ok(())
 }
+
+fn main() -> Result<(), Box<dyn Error + 'static>> {
+    let mut args = env::args();
+    let bin = args.next().unwrap_or_else(|| String::from("yanes"));
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, Options::usage(&bin));
+            process::exit(2);
+        }
+    };
+
+    let bytes = fs::read(&options.program)?;
+
+    let mut cpu = CPU::new();
+    if let Some(dir) = options.sandbox.clone() {
+        cpu.set_sandbox(dir);
+    }
+
+    if let Some(mode) = options.rng {
+        cpu.bus_mut().rng_mut().set_mode(mode);
+    }
+
+    if let Some(source) = options.rtc {
+        cpu.bus_mut().rtc_mut().set_source(source);
+    }
+
+    if let Some(ClockSpeed::Hz(hz)) = options.clock {
+        cpu.bus_mut().apu_mut().set_clock(hz);
+    }
+
+    if let Err(e) = boot(&mut cpu, &bytes, &options) {
+        eprintln!("{}\n\n{}", e, Options::usage(&bin));
+        process::exit(2);
+    }
+
+    let mut wav = match &options.wav {
+        Some(path) => {
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: SAMPLE_RATE,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+
+            Some(hound::WavWriter::create(path, spec)?)
+        }
+        None => None,
+    };
+
+    let mut recorder = options
+        .record
+        .as_ref()
+        .map(|path| Recorder::new(path, options.capture_scale));
+
+    let debug_info = match &options.debug_info {
+        Some(path) => DebugInfo::load(path)?,
+        None => DebugInfo::default(),
+    };
+
+    let run = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), Box<dyn Error>> {
+        if let Some(port) = options.gdb {
+            cpu.bus_mut().apu_mut().set_enabled(wav.is_some());
+
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+            gdb::serve(&mut cpu, listener)?;
+        } else if let Some(port) = options.dap {
+            cpu.bus_mut().apu_mut().set_enabled(wav.is_some());
+
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("Waiting for a DAP client on {}", listener.local_addr()?);
+            dap::serve(&mut cpu, listener, debug_info.clone())?;
+        } else if options.headless {
+            cpu.bus_mut().apu_mut().set_enabled(wav.is_some());
+            let mut pacer = FramePacer::new(options.clock.unwrap_or(ClockSpeed::Unthrottled));
+            let mut frame = Frame::new();
+            while run_frame(&mut cpu, None, &mut wav) {
+                report_stack_warnings(&mut cpu);
+                if recorder.is_some() {
+                    let changed = frame.update(cpu.bus_mut());
+                    record_frame(&mut recorder, &frame, changed);
+                }
+
+                pacer.wait();
+            }
+        } else if options.tui {
+            run_tui(&mut cpu, &mut wav, &mut recorder, &options)?;
+        } else {
+            run_sdl(
+                &mut cpu,
+                &bytes,
+                &mut wav,
+                &mut recorder,
+                &options,
+                &debug_info,
+            );
+        }
+
+        Ok(())
+    }));
+
+    // The terminal frontend can't show warnings while it runs
+    report_stack_warnings(&mut cpu);
+    match run {
+        Ok(result) => result?,
+        Err(panic) => {
+            print_backtrace(&cpu, &debug_info);
+            panic::resume_unwind(panic);
+        }
+    }
+
+    if let Some(path) = &options.screenshot {
+        let mut frame = Frame::new();
+        frame.update(cpu.bus_mut());
+        save_png(&frame, options.capture_scale, path)?;
+    }
+
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+
+    if let Some(path) = &options.dump_memory {
+        let memory = (0..=u16::MAX)
+            .map(|addr| cpu.bus().peek(addr))
+            .collect::<Vec<_>>();
+
+        fs::write(path, memory)?;
+    }
+
+    drain_audio(&mut cpu, None, &mut wav);
+    if let Some(wav) = wav {
+        wav.finalize()?;
+    }
+
+    println!("{:?}", cpu);
+    process::exit(cpu.exit_code().unwrap_or(0) as i32);
+}
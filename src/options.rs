@@ -0,0 +1,247 @@
+use std::env;
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+
+use crate::bus::rng::RngMode;
+use crate::bus::rtc::RtcSource;
+
+#[derive(Clone, Copy)]
+pub enum ClockSpeed {
+    Hz(u64),
+    Unthrottled,
+}
+
+#[derive(Clone, Copy)]
+pub enum Filter {
+    Nearest,
+    Linear,
+}
+
+pub struct Options {
+    pub program: PathBuf,
+    pub headless: bool,
+    pub tui: bool,
+    pub sandbox: Option<PathBuf>,
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    pub rng: Option<RngMode>,
+    pub rtc: Option<RtcSource>,
+    pub wav: Option<PathBuf>,
+    pub clock: Option<ClockSpeed>,
+    pub scale: Option<u32>,
+    pub fullscreen: bool,
+    pub filter: Filter,
+    pub screenshot: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub capture_scale: u32,
+    pub dump_memory: Option<PathBuf>,
+    pub gdb: Option<u16>,
+    pub dap: Option<u16>,
+    pub debug_info: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn usage(bin: &str) -> String {
+        format!(
+            "Usage: {} [options] [--] program.bin [args...]\n\n\
+             Options:\n    \
+             --headless         Run without a window (no screen, no keyboard)\n    \
+             --tui              Show the screen in the terminal instead of a window\n    \
+             --sandbox <dir>    Directory the program may access through host syscalls\n    \
+             --env <var[=val]>  Pass an environment variable to the program (repeatable)\n    \
+             --rng-seed <n>     Seed the random number generator for reproducible runs\n    \
+             --rng-entropy      Make the random number generator return true random bytes\n    \
+             --rtc-time <time>  Freeze the real-time clock at \"YYYY-MM-DD HH:MM:SS\"\n    \
+             --wav <file>       Record the sound output into a WAV file\n    \
+             --clock <speed>    CPU speed, e.g. 1MHz (default with a window), 2MHz or unthrottled (default when headless)\n    \
+             --scale <n>        Initial window size as a multiple of the screen resolution\n    \
+             --fullscreen       Start in fullscreen mode (F11 toggles it)\n    \
+             --filter <filter>  Scaling filter: nearest (default) or linear\n    \
+             --screenshot <file>\n                        Save the screen as PNG when the program halts (F12 saves one any time)\n    \
+             --record <path>    Record the screen into a GIF (path ending with .gif) or a directory of PNG frames (F10 toggles GIF recording)\n    \
+             --capture-scale <n>\n                        Scale factor of screenshots and recordings (default 1)\n    \
+             --dump-memory <file>\n                        Save the 64 KB address space into a file when the program halts or yanes is closed\n    \
+             --gdb <port>       Wait for GDB to connect on a local TCP port and run the program under its control\n    \
+             --dap <port>       Wait for a Debug Adapter Protocol client (e.g. VS Code) on a local TCP port\n    \
+             --debug-info <file>\n                        Source lines and labels of the program, for --dap, the debugger window and backtraces",
+            bin
+        )
+    }
+
+    pub fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut program = None;
+        let mut headless = false;
+        let mut tui = false;
+        let mut sandbox = None;
+        let mut guest_args = vec![];
+        let mut env = vec![];
+        let mut rng = None;
+        let mut rtc = None;
+        let mut wav = None;
+        let mut clock_speed = None;
+        let mut scale = None;
+        let mut fullscreen = false;
+        let mut filter = Filter::Nearest;
+        let mut screenshot = None;
+        let mut record = None;
+        let mut capture_scale = 1;
+        let mut dump_memory = None;
+        let mut gdb = None;
+        let mut dap = None;
+        let mut debug_info = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                // Ends the options, the program path comes next if it wasn't given yet
+                "--" => {
+                    if program.is_none() {
+                        program = args.next().map(PathBuf::from);
+                    }
+                    guest_args.extend(args.by_ref());
+                }
+                "--headless" => headless = true,
+                "--tui" => tui = true,
+                "--sandbox" => sandbox = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--env" => env.push(env_var(value(&mut args, &arg)?)?),
+                "--rng-seed" => rng = Some(RngMode::Seeded(number(&value(&mut args, &arg)?)?)),
+                "--rng-entropy" => rng = Some(RngMode::Entropy),
+                "--rtc-time" => rtc = Some(RtcSource::Frozen(time(&value(&mut args, &arg)?)?)),
+                "--wav" => wav = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--clock" => clock_speed = Some(clock(&value(&mut args, &arg)?)?),
+                "--scale" => scale = Some(scale_factor(&value(&mut args, &arg)?)?),
+                "--fullscreen" => fullscreen = true,
+                "--screenshot" => screenshot = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--record" => record = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--capture-scale" => capture_scale = scale_factor(&value(&mut args, &arg)?)?,
+                "--dump-memory" => dump_memory = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--gdb" => gdb = Some(port(&value(&mut args, &arg)?)?),
+                "--dap" => dap = Some(port(&value(&mut args, &arg)?)?),
+                "--debug-info" => debug_info = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--filter" => {
+                    filter = match value(&mut args, &arg)?.as_str() {
+                        "nearest" => Filter::Nearest,
+                        "linear" => Filter::Linear,
+                        other => return Err(format!("Unknown filter: {}", other)),
+                    }
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                _ if program.is_none() => program = Some(PathBuf::from(arg)),
+                _ => guest_args.push(arg),
+            }
+        }
+
+        let program = program.ok_or_else(|| String::from("No program specified"))?;
+
+        let frontends = [
+            ("--gdb", gdb.is_some()),
+            ("--dap", dap.is_some()),
+            ("--headless", headless),
+            ("--tui", tui),
+        ];
+        let chosen: Vec<_> = frontends
+            .iter()
+            .filter(|(_, chosen)| *chosen)
+            .map(|(name, _)| *name)
+            .collect();
+        if chosen.len() > 1 {
+            return Err(format!("Options {} can't be combined", chosen.join(", ")));
+        }
+
+        guest_args.insert(0, program.to_string_lossy().into_owned());
+
+        Ok(Options {
+            program,
+            headless,
+            tui,
+            sandbox,
+            args: guest_args,
+            env,
+            rng,
+            rtc,
+            wav,
+            clock: clock_speed,
+            scale,
+            fullscreen,
+            filter,
+            screenshot,
+            record,
+            capture_scale,
+            dump_memory,
+            gdb,
+            dap,
+            debug_info,
+        })
+    }
+}
+
+fn value<I>(args: &mut I, option: &str) -> Result<String, String>
+where
+    I: Iterator<Item = String>,
+{
+    args.next()
+        .ok_or_else(|| format!("Option {} requires a value", option))
+}
+
+fn number(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix('$')) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    parsed.map_err(|_| format!("Invalid number: {}", s))
+}
+
+fn time(s: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S"))
+        .map_err(|_| format!("Invalid time: {}", s))
+}
+
+fn clock(s: &str) -> Result<ClockSpeed, String> {
+    let lower = s.to_ascii_lowercase();
+    if lower == "unthrottled" {
+        return Ok(ClockSpeed::Unthrottled);
+    }
+
+    let (digits, multiplier) = if let Some(digits) = lower.strip_suffix("mhz") {
+        (digits, 1e6)
+    } else if let Some(digits) = lower.strip_suffix("khz") {
+        (digits, 1e3)
+    } else {
+        (lower.strip_suffix("hz").unwrap_or(&lower), 1.0)
+    };
+
+    match digits.trim().parse::<f64>() {
+        Ok(value) if value * multiplier >= 1.0 => Ok(ClockSpeed::Hz((value * multiplier) as u64)),
+        _ => Err(format!("Invalid clock speed: {}", s)),
+    }
+}
+
+fn scale_factor(s: &str) -> Result<u32, String> {
+    match number(s)? {
+        factor @ 1..=64 => Ok(factor as u32),
+        _ => Err(format!("Invalid scale factor: {}", s)),
+    }
+}
+
+fn port(s: &str) -> Result<u16, String> {
+    match number(s)? {
+        port @ 1..=0xFFFF => Ok(port as u16),
+        _ => Err(format!("Invalid port: {}", s)),
+    }
+}
+
+fn env_var(spec: String) -> Result<String, String> {
+    if spec.contains('=') {
+        return Ok(spec);
+    }
+
+    env::var(&spec)
+        .map(|val| format!("{}={}", spec, val))
+        .map_err(|_| format!("Environment variable {} is not set", spec))
+}
@@ -0,0 +1,177 @@
+use std::mem;
+
+use crate::bus::display::GraphicsMode;
+use crate::bus::sprites::{SPRITE_COUNT, SPRITE_SIZE};
+use crate::bus::Bus;
+use crate::cpu::MemLayout;
+use crate::font::{self, GLYPH_SIZE};
+
+const VGA_PAGE_SIZE: u16 = MemLayout::VGA_BUF_END - MemLayout::VGA_BUF_START + 1;
+const TEXT_COLUMNS: u32 = 40;
+// Attribute byte 0 (black on black) shows white on black instead
+const DEFAULT_TEXT_ATTR: u8 = 0x01;
+
+// RGB24 image of what the program displays, shared by the frontends
+pub struct Frame {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    stale: bool,
+    // Pixels covered by sprites in the last VGA frame
+    sprite_area: Vec<usize>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        let (width, height) = GraphicsMode::Vga.resolution();
+        Frame {
+            width,
+            height,
+            pixels: vec![0; (width * height * 3) as usize],
+            stale: true,
+            sprite_area: vec![],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pitch(&self) -> usize {
+        self.width as usize * 3
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // Returns whether the frame changed
+    pub fn update(&mut self, bus: &mut Bus) -> bool {
+        let mode = bus.display().mode();
+        let (width, height) = mode.resolution();
+        let resized = (width, height) != (self.width, self.height);
+        if resized {
+            self.width = width;
+            self.height = height;
+            self.pixels = vec![0; (width * height * 3) as usize];
+        }
+
+        let redraw = mem::take(&mut self.stale) | bus.display_mut().take_dirty() | resized;
+        match mode {
+            GraphicsMode::Vga => self.update_vga(bus, redraw) || resized,
+            _ => {
+                if !redraw {
+                    return false;
+                }
+
+                match mode {
+                    GraphicsMode::Text40x25 => self.update_text(bus),
+                    _ => self.update_bitmap(bus),
+                }
+
+                true
+            }
+        }
+    }
+
+    // Only redraws the written pixels and the ones under sprites, unless `redraw` is set.
+    // Sprites are drawn over the VGA Buffer page being shown.
+    fn update_vga(&mut self, bus: &mut Bus, redraw: bool) -> bool {
+        let page_start = bus.display().vga_page_start();
+        let written = bus.take_vga_writes();
+        let sprite_area = self.sprite_area(bus);
+
+        let pixels = if redraw {
+            (0..VGA_PAGE_SIZE as usize).collect::<Vec<_>>()
+        } else {
+            written
+                .into_iter()
+                .filter(|addr| (page_start..page_start + VGA_PAGE_SIZE).contains(addr))
+                .map(|addr| (addr - page_start) as usize)
+                .chain(self.sprite_area.iter().copied())
+                .chain(sprite_area.iter().copied())
+                .collect()
+        };
+
+        self.sprite_area = sprite_area;
+
+        let mut update = false;
+        for i in pixels {
+            let addr = page_start + i as u16;
+            let (x, y) = (i as u32 % self.width, i as u32 / self.width);
+            let color_idx = bus.sprite_pixel(x, y).unwrap_or_else(|| bus.peek(addr));
+
+            let rgb = bus.palette().rgb(color_idx);
+            let pixel = &mut self.pixels[i * 3..i * 3 + 3];
+            if pixel != rgb {
+                pixel.copy_from_slice(&rgb);
+                update = true;
+            }
+        }
+
+        update
+    }
+
+    fn sprite_area(&self, bus: &Bus) -> Vec<usize> {
+        let mut area = vec![];
+        for idx in 0..SPRITE_COUNT {
+            let sprite = bus.sprites().sprite(idx);
+            if !sprite.enabled {
+                continue;
+            }
+
+            for y in (sprite.y as u32..).take(SPRITE_SIZE as usize) {
+                for x in (sprite.x as u32..).take(SPRITE_SIZE as usize) {
+                    if x < self.width && y < self.height {
+                        area.push((y * self.width + x) as usize);
+                    }
+                }
+            }
+        }
+
+        area
+    }
+
+    // Two pixels per byte, the left one in the high nibble
+    fn update_bitmap(&mut self, bus: &Bus) {
+        let vram = bus.display().vram();
+        for i in 0..(self.width * self.height) as usize {
+            let byte = vram[i / 2];
+            let color_idx = if i % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+            self.pixels[i * 3..i * 3 + 3].copy_from_slice(&bus.palette().rgb(color_idx));
+        }
+    }
+
+    // Attribute bytes hold the foreground color in the low nibble and the background in the high one
+    fn update_text(&mut self, bus: &Bus) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let cell = (y / GLYPH_SIZE * TEXT_COLUMNS + x / GLYPH_SIZE) as u16;
+                let charcode = bus.peek(MemLayout::TEXT_BUF_START + cell);
+                let attr = match bus.peek(MemLayout::TEXT_ATTR_START + cell) {
+                    0 => DEFAULT_TEXT_ATTR,
+                    attr => attr,
+                };
+
+                let color_idx = if font::pixel(charcode, x % GLYPH_SIZE, y % GLYPH_SIZE) {
+                    attr & 0x0F
+                } else {
+                    attr >> 4
+                };
+
+                let i = (y * self.width + x) as usize;
+                self.pixels[i * 3..i * 3 + 3].copy_from_slice(&bus.palette().rgb(color_idx));
+            }
+        }
+    }
+}
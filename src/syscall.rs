@@ -0,0 +1,265 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cpu::{MemLayout, RAMAccess};
+
+// `BRK` followed by this byte traps into the host instead of raising an IRQ.
+// It is not a valid opcode, so it can't be mistaken for a resume point.
+pub const SYSCALL_SIGNATURE: u8 = 0x80;
+
+const MAX_HANDLES: usize = 16;
+const MAX_PATH_LEN: u16 = 0xFF;
+
+pub struct Syscall;
+impl Syscall {
+    pub const EXIT: u8 = 0x00;
+    pub const OPEN: u8 = 0x01;
+    pub const CLOSE: u8 = 0x02;
+    pub const READ: u8 = 0x03;
+    pub const WRITE: u8 = 0x04;
+    pub const TIME: u8 = 0x05;
+    pub const RANDOM: u8 = 0x06;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyscallError {
+    NoSuchService = 0x01,
+    BadHandle = 0x02,
+    NotFound = 0x03,
+    Denied = 0x04,
+    TooManyHandles = 0x05,
+    InvalidArgument = 0x06,
+    Io = 0x07,
+}
+
+impl From<io::Error> for SyscallError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => SyscallError::NotFound,
+            io::ErrorKind::PermissionDenied => SyscallError::Denied,
+            _ => SyscallError::Io,
+        }
+    }
+}
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct HostServices {
+    sandbox: Option<PathBuf>,
+    handles: Vec<Option<Handle>>,
+}
+
+impl Default for HostServices {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostServices {
+    pub fn new() -> Self {
+        let mut handles = vec![
+            Some(Handle::Stdin),
+            Some(Handle::Stdout),
+            Some(Handle::Stderr),
+        ];
+
+        handles.resize_with(MAX_HANDLES, || None);
+
+        HostServices {
+            sandbox: None,
+            handles,
+        }
+    }
+
+    pub fn set_sandbox(&mut self, dir: PathBuf) {
+        self.sandbox = Some(dir);
+    }
+
+    pub fn close_files(&mut self) {
+        for handle in self.handles.iter_mut() {
+            if let Some(Handle::File(_)) = handle {
+                *handle = None;
+            }
+        }
+    }
+
+    pub fn call(
+        &mut self,
+        service: u8,
+        block: u16,
+        mem: &mut dyn RAMAccess,
+    ) -> Result<u8, SyscallError> {
+        match service {
+            Syscall::EXIT => {
+                mem.mem_write_byte(MemLayout::EXIT_PORT, mem.mem_read_byte(block));
+                Ok(0)
+            }
+
+            Syscall::OPEN => {
+                let path = self.resolve(&read_cstr(mem, mem.mem_read_word(block))?)?;
+                let file = match mem.mem_read_byte(block.wrapping_add(2)) {
+                    0 => File::open(path)?,
+                    1 => File::create(path)?,
+                    2 => OpenOptions::new().append(true).create(true).open(path)?,
+                    _ => return Err(SyscallError::InvalidArgument),
+                };
+
+                let idx = self
+                    .handles
+                    .iter()
+                    .position(Option::is_none)
+                    .ok_or(SyscallError::TooManyHandles)?;
+
+                self.handles[idx] = Some(Handle::File(file));
+                Ok(idx as u8)
+            }
+
+            Syscall::CLOSE => {
+                let idx = mem.mem_read_byte(block) as usize;
+                match self.handles.get_mut(idx) {
+                    Some(handle @ Some(_)) => {
+                        *handle = None;
+                        Ok(0)
+                    }
+                    _ => Err(SyscallError::BadHandle),
+                }
+            }
+
+            Syscall::READ => {
+                let (handle, buf_addr, len) = self.io_args(block, mem)?;
+                let mut buf = vec![0u8; len as usize];
+                let count = match handle {
+                    Handle::Stdin => io::stdin().read(&mut buf)?,
+                    Handle::File(file) => file.read(&mut buf)?,
+                    _ => return Err(SyscallError::BadHandle),
+                };
+
+                write_block(mem, buf_addr, &buf[..count]);
+                mem.mem_write_word(block.wrapping_add(5), count as u16);
+                Ok(0)
+            }
+
+            Syscall::WRITE => {
+                let (handle, buf_addr, len) = self.io_args(block, mem)?;
+                let buf = (0..len)
+                    .map(|i| mem.mem_read_byte(buf_addr.wrapping_add(i)))
+                    .collect::<Vec<_>>();
+
+                let count = match handle {
+                    Handle::Stdout => {
+                        let mut stdout = io::stdout();
+                        let count = stdout.write(&buf)?;
+                        stdout.flush()?;
+                        count
+                    }
+                    Handle::Stderr => io::stderr().write(&buf)?,
+                    Handle::File(file) => file.write(&buf)?,
+                    _ => return Err(SyscallError::BadHandle),
+                };
+
+                mem.mem_write_word(block.wrapping_add(5), count as u16);
+                Ok(0)
+            }
+
+            Syscall::TIME => {
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|_| SyscallError::Io)?
+                    .as_secs() as u32;
+
+                write_block(mem, block, &secs.to_le_bytes());
+                Ok(0)
+            }
+
+            Syscall::RANDOM => {
+                let buf_addr = mem.mem_read_word(block);
+                let buf = (0..mem.mem_read_word(block.wrapping_add(2)))
+                    .map(|_| mem.mem_read_byte(MemLayout::RNG_PORT))
+                    .collect::<Vec<_>>();
+
+                write_block(mem, buf_addr, &buf);
+                Ok(0)
+            }
+
+            _ => Err(SyscallError::NoSuchService),
+        }
+    }
+
+    fn io_args(
+        &mut self,
+        block: u16,
+        mem: &dyn RAMAccess,
+    ) -> Result<(&mut Handle, u16, u16), SyscallError> {
+        let idx = mem.mem_read_byte(block) as usize;
+        let handle = self
+            .handles
+            .get_mut(idx)
+            .and_then(Option::as_mut)
+            .ok_or(SyscallError::BadHandle)?;
+
+        Ok((
+            handle,
+            mem.mem_read_word(block.wrapping_add(1)),
+            mem.mem_read_word(block.wrapping_add(3)),
+        ))
+    }
+
+    fn resolve(&self, name: &[u8]) -> Result<PathBuf, SyscallError> {
+        let sandbox = self.sandbox.as_ref().ok_or(SyscallError::Denied)?;
+        let name = std::str::from_utf8(name).map_err(|_| SyscallError::InvalidArgument)?;
+        let path = Path::new(name);
+
+        if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(SyscallError::Denied);
+        }
+
+        // Follow symlinks, so that a link inside the sandbox can't lead out of it. A file that
+        // doesn't exist yet is checked through its parent directory.
+        let sandbox = sandbox.canonicalize()?;
+        let joined = sandbox.join(path);
+        let resolved = match joined.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            // A dangling symlink, which creating the file would follow
+            Err(_) if joined.symlink_metadata().is_ok() => return Err(SyscallError::Denied),
+            Err(_) => {
+                let parent = joined
+                    .parent()
+                    .ok_or(SyscallError::Denied)?
+                    .canonicalize()?;
+                parent.join(path.file_name().ok_or(SyscallError::Denied)?)
+            }
+        };
+
+        if !resolved.starts_with(&sandbox) {
+            return Err(SyscallError::Denied);
+        }
+
+        Ok(resolved)
+    }
+}
+
+fn read_cstr(mem: &dyn RAMAccess, addr: u16) -> Result<Vec<u8>, SyscallError> {
+    let mut bytes = vec![];
+    for i in 0..MAX_PATH_LEN {
+        match mem.mem_read_byte(addr.wrapping_add(i)) {
+            0 => return Ok(bytes),
+            b => bytes.push(b),
+        }
+    }
+
+    Err(SyscallError::InvalidArgument)
+}
+
+fn write_block(mem: &mut dyn RAMAccess, addr: u16, bytes: &[u8]) {
+    for (i, b) in bytes.iter().enumerate() {
+        mem.mem_write_byte(addr.wrapping_add(i as u16), *b);
+    }
+}
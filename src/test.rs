@@ -2,7 +2,17 @@ use std::sync::{LockResult, Mutex};
 
 use lazy_static::lazy_static;
 
-use crate::cpu::CPU;
+use chrono::NaiveDate;
+
+use crate::bus::display::{CYCLES_PER_FRAME, VBLANK_START};
+use crate::bus::rng::RngMode;
+use crate::bus::rtc::RtcSource;
+use crate::capture::{save_png, Recorder};
+use crate::cpu::{RAMAccess, CPU};
+use crate::disasm::{disassemble, instructions_around};
+use crate::keyboard;
+use crate::screen::Frame;
+use crate::syscall::SyscallError;
 
 lazy_static! {
     static ref CPU_SINGLETON: Mutex<CPU> = Mutex::new(CPU::new());
@@ -681,3 +691,1308 @@ fn test_0x0d_bne() {
     assert!(!cpu.p().N());
     assert!(cpu.p().Z());
 }
+
+#[test]
+fn test_exit_port_halts() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x2a, // mov $a, 0x2a
+        0x8d, 0x00, 0x7f, // mov byte ptr [0x7F00], $a
+        0xa9, 0x01, // mov $a, 0x01
+        0x00,
+    ]);
+    cpu.run();
+
+    assert!(cpu.exit_code() == Some(0x2a));
+    assert!(*cpu.a() == 0x2a); // halted right after the write
+}
+
+#[test]
+fn test_run_with_callback() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x01, // mov $a, 0x01
+        0x8d, 0x00, 0x7f, // mov byte ptr [0x7F00], $a
+    ]);
+    let mut pcs = vec![];
+    cpu.run_with_callback(|cpu| pcs.push(*cpu.pc()));
+
+    // The instruction that writes the exit port is called back for
+    assert!(pcs == vec![0x8002, 0x8005]);
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x01, // mov $a, 0x01
+        0x00,
+    ]);
+    let mut pcs = vec![];
+    cpu.run_with_callback(|cpu| pcs.push(*cpu.pc()));
+
+    assert!(pcs == vec![0x8002]);
+}
+
+#[test]
+fn test_brk_without_exit_code() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![0xa9, 0x05, 0x00]);
+    cpu.run();
+
+    assert!(cpu.exit_code().is_none());
+}
+
+#[test]
+fn test_syscall_write_read_file() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let sandbox = std::env::temp_dir().join("yanes_test_syscall");
+    std::fs::create_dir_all(&sandbox).unwrap();
+
+    cpu.reset();
+    cpu.set_sandbox(sandbox.clone());
+    cpu.mem_write_word(0x10, 0x0700); // path
+    cpu.mem_write_byte(0x12, 0x01); // mode: create
+    cpu.mem_write_byte(0x0700, b'a');
+    cpu.mem_write_byte(0x0701, 0x00);
+    cpu.mem_write_word(0x21, 0x0710); // buffer
+    cpu.mem_write_word(0x23, 0x0002); // length
+    cpu.mem_write_byte(0x0710, 0x13);
+    cpu.mem_write_byte(0x0711, 0x37);
+    cpu.load(vec![
+        0xa2, 0x10, // mov $x, 0x10
+        0xa0, 0x00, // mov $y, 0x00
+        0xa9, 0x01, // mov $a, OPEN
+        0x00, 0x80, // syscall
+        0x85, 0x20, // mov byte ptr [0x20], $a
+        0xa2, 0x20, // mov $x, 0x20
+        0xa9, 0x04, // mov $a, WRITE
+        0x00, 0x80, // syscall
+        0xa9, 0x02, // mov $a, CLOSE
+        0x00, 0x80, // syscall
+        0x00,
+    ]);
+    cpu.run();
+
+    assert!(!cpu.p().C());
+    assert!(cpu.mem_read_word(0x25) == 2);
+    assert!(std::fs::read(sandbox.join("a")).unwrap() == vec![0x13, 0x37]);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_syscall_symlink_out_of_sandbox() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let sandbox = std::env::temp_dir().join("yanes_test_syscall_symlink");
+    let outside = std::env::temp_dir().join("yanes_test_syscall_outside");
+    let _ = std::fs::remove_dir_all(&sandbox);
+    let _ = std::fs::remove_dir_all(&outside);
+    std::fs::create_dir_all(&sandbox).unwrap();
+    std::fs::create_dir_all(&outside).unwrap();
+    std::os::unix::fs::symlink(&outside, sandbox.join("out")).unwrap();
+    std::os::unix::fs::symlink(outside.join("b"), sandbox.join("b")).unwrap();
+
+    cpu.reset();
+    cpu.set_sandbox(sandbox);
+    cpu.mem_write_word(0x10, 0x0700); // path "out/a"
+    cpu.mem_write_byte(0x12, 0x01); // mode: create
+    cpu.mem_write_word(0x18, 0x0710); // path "b"
+    cpu.mem_write_byte(0x1A, 0x01); // mode: create
+    for (i, b) in b"out/a\0".iter().enumerate() {
+        cpu.mem_write_byte(0x0700 + i as u16, *b);
+    }
+    cpu.mem_write_byte(0x0710, b'b');
+    cpu.mem_write_byte(0x0711, 0x00);
+    cpu.load(vec![
+        0xa2, 0x10, // mov $x, 0x10
+        0xa0, 0x00, // mov $y, 0x00
+        0xa9, 0x01, // mov $a, OPEN
+        0x00, 0x80, // syscall
+        0x85, 0x30, // mov byte ptr [0x30], $a
+        0xa2, 0x18, // mov $x, 0x18
+        0xa9, 0x01, // mov $a, OPEN
+        0x00, 0x80, // syscall
+        0x85, 0x31, // mov byte ptr [0x31], $a
+        0x00,
+    ]);
+    cpu.run();
+
+    assert!(cpu.mem_read_byte(0x30) == SyscallError::Denied as u8);
+    assert!(cpu.mem_read_byte(0x31) == SyscallError::Denied as u8);
+    assert!(!outside.join("a").exists());
+    assert!(!outside.join("b").exists());
+}
+
+#[test]
+fn test_syscall_errors() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.mem_write_word(0x10, 0x0700);
+    cpu.mem_write_byte(0x0700, b'.');
+    cpu.mem_write_byte(0x0701, b'.');
+    cpu.load(vec![
+        0xa2, 0x10, // mov $x, 0x10
+        0xa9, 0x01, // mov $a, OPEN
+        0x00, 0x80, // syscall
+        0x85, 0x30, // mov byte ptr [0x30], $a
+        0xa9, 0x7f, // mov $a, 0x7f
+        0x00, 0x80, // syscall
+        0x00,
+    ]);
+    cpu.run();
+
+    assert!(cpu.mem_read_byte(0x30) == SyscallError::Denied as u8);
+    assert!(*cpu.a() == SyscallError::NoSuchService as u8);
+    assert!(cpu.p().C());
+}
+
+#[test]
+fn test_syscall_block_wraps_around() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa2, 0xfe, // mov $x, 0xfe
+        0xa0, 0xff, // mov $y, 0xff
+        0xa9, 0x04, // mov $a, WRITE
+        0x00, 0x80, // syscall
+        // The block overwrites the IRQ vector, so BRK can't halt
+        0x8d, 0x00, 0x7f, // mov byte ptr [0x7F00], $a
+    ]);
+    cpu.mem_write_byte(0xFFFE, 0x01); // handle: stdout
+    cpu.mem_write_word(0xFFFF, 0x0700); // buffer
+    cpu.mem_write_word(0x0001, 0x0000); // length
+    cpu.mem_write_word(0x0003, 0xFFFF);
+    cpu.run();
+
+    assert!(cpu.exit_code() == Some(0));
+    assert!(!cpu.p().C());
+    assert!(cpu.mem_read_word(0x0003) == 0);
+}
+
+#[test]
+fn test_load_args_layout() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![0x00]);
+    cpu.load_args(
+        &[String::from("a.bin"), String::from("x")],
+        &[String::from("K=v")],
+    )
+    .unwrap();
+
+    assert!(cpu.mem_read_byte(0x7E00) == 2); // argc
+    assert!(cpu.mem_read_byte(0x7E01) == 1); // envc
+    assert!(cpu.mem_read_word(0x7E02) == 0x7E0C); // argv[0]
+    assert!(cpu.mem_read_word(0x7E04) == 0x7E12); // argv[1]
+    assert!(cpu.mem_read_word(0x7E06) == 0x0000);
+    assert!(cpu.mem_read_word(0x7E08) == 0x7E14); // envp[0]
+    assert!(cpu.mem_read_word(0x7E0A) == 0x0000);
+    assert!(cpu.mem_read_byte(0x7E12) == b'x');
+    assert!(cpu.mem_read_byte(0x7E13) == 0x00);
+    assert!(cpu.mem_read_byte(0x7E16) == b'v');
+}
+
+#[test]
+fn test_load_args_overflow() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    assert!(cpu.load_args(&["x".repeat(0x100)], &[]).is_err());
+    assert!(cpu.load_args(&vec![String::new(); 0x100], &[]).is_err());
+    assert!(cpu.load_args(&[], &vec![String::new(); 0x100]).is_err());
+}
+
+#[test]
+fn test_options_clock() {
+    use crate::options::{ClockSpeed, Options};
+
+    let clock = |speed: &str| {
+        let args = ["a.bin", "--clock", speed].map(String::from);
+        Options::parse(args).map(|options| match options.clock {
+            Some(ClockSpeed::Hz(hz)) => Some(hz),
+            _ => None,
+        })
+    };
+
+    assert!(clock("1MHz") == Ok(Some(1_000_000)));
+    assert!(clock("1.79mhz") == Ok(Some(1_790_000)));
+    assert!(clock("500kHz") == Ok(Some(500_000)));
+    assert!(clock("60Hz") == Ok(Some(60)));
+    assert!(clock("2000") == Ok(Some(2000)));
+    assert!(clock("Unthrottled") == Ok(None));
+
+    assert!(clock("0Hz").is_err());
+    assert!(clock("0.5").is_err());
+    assert!(clock("-1MHz").is_err());
+    assert!(clock("fast").is_err());
+    assert!(clock("MHz").is_err());
+}
+
+#[test]
+fn test_frame_pacer() {
+    use std::time::{Duration, Instant};
+
+    use crate::options::ClockSpeed;
+    use crate::{FramePacer, MAX_LAG_FRAMES};
+
+    let frame_time = Duration::from_nanos(CYCLES_PER_FRAME * 1000);
+    let start = Instant::now();
+    let mut pacer = FramePacer::new(ClockSpeed::Hz(1_000_000));
+    pacer.deadline = start;
+
+    // Sleeps until the end of the frame, minus the time spent emulating it
+    assert!(pacer.delay(start) == Some(frame_time));
+
+    // Catches up after falling behind, keeping to the schedule
+    assert!(pacer.delay(start + frame_time * 5 / 2).is_none());
+    assert!(pacer.delay(start + frame_time * 5 / 2) == Some(frame_time / 2));
+
+    // Gives up catching up after a long stall
+    let stall = start + frame_time * (10 + MAX_LAG_FRAMES);
+    assert!(pacer.delay(stall).is_none());
+    assert!(pacer.delay(stall) == Some(frame_time));
+
+    let mut unthrottled = FramePacer::new(ClockSpeed::Unthrottled);
+    assert!(unthrottled.delay(start).is_none());
+}
+
+#[test]
+fn test_options_reject_conflicting_frontends() {
+    use crate::options::Options;
+
+    let parse = |args: &[&str]| Options::parse(args.iter().map(|arg| arg.to_string()));
+
+    assert!(parse(&["a.bin", "--tui"]).unwrap().tui);
+    assert!(parse(&["a.bin", "--gdb", "1234", "--debug-info", "a.dbg"]).is_ok());
+    assert!(
+        parse(&["a.bin", "--gdb", "1234", "--headless"]).err()
+            == Some(String::from("Options --gdb, --headless can't be combined"))
+    );
+    assert!(parse(&["a.bin", "--dap", "1234", "--gdb", "1235"]).is_err());
+    assert!(parse(&["--headless", "a.bin", "--tui"]).is_err());
+}
+
+#[test]
+fn test_options_end_of_options() {
+    use crate::options::Options;
+
+    let parse = |args: &[&str]| Options::parse(args.iter().map(|arg| arg.to_string()));
+
+    let options = parse(&["--tui", "--", "--a.bin", "--headless", "x"]).unwrap();
+    assert!(options.tui && !options.headless);
+    assert!(options.program == std::path::Path::new("--a.bin"));
+    assert!(options.args == vec!["--a.bin", "--headless", "x"]);
+
+    let options = parse(&["a.bin", "--", "--tui"]).unwrap();
+    assert!(!options.tui && options.args == vec!["a.bin", "--tui"]);
+
+    assert!(parse(&["--tui", "--"]).err() == Some(String::from("No program specified")));
+}
+
+#[test]
+fn test_rng_seeded_is_reproducible() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let program = vec![
+        0xad, 0x01, 0x7f, // mov $a, byte ptr [0x7F01]
+        0x85, 0x10, // mov byte ptr [0x10], $a
+        0xad, 0x01, 0x7f, // mov $a, byte ptr [0x7F01]
+        0x85, 0x11, // mov byte ptr [0x11], $a
+        0x00,
+    ];
+
+    cpu.bus_mut().rng_mut().set_mode(RngMode::Seeded(42));
+    cpu.reset();
+    cpu.load(program.clone());
+    cpu.run();
+    let first = cpu.mem_read_word(0x10);
+
+    cpu.reset();
+    cpu.load(program);
+    cpu.run();
+
+    assert!(cpu.mem_read_word(0x10) == first);
+    assert!(cpu.mem_read_byte(0x10) != cpu.mem_read_byte(0x11));
+}
+
+#[test]
+fn test_rtc_frozen_bcd() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let time = NaiveDate::from_ymd_opt(2026, 10, 18)
+        .and_then(|date| date.and_hms_opt(23, 59, 7))
+        .unwrap();
+
+    cpu.bus_mut().rtc_mut().set_source(RtcSource::Frozen(time));
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x01, // mov $a, 0x01
+        0x8d, 0x08, 0x7f, // mov byte ptr [0x7F08], $a
+        0xae, 0x09, 0x7f, // mov $x, byte ptr [0x7F09]
+        0xac, 0x0b, 0x7f, // mov $y, byte ptr [0x7F0B]
+        0xad, 0x0f, 0x7f, // mov $a, byte ptr [0x7F0F]
+        0x00,
+    ]);
+    cpu.run();
+
+    assert!(*cpu.x() == 0x07);
+    assert!(*cpu.y() == 0x23);
+    assert!(*cpu.a() == 0x20);
+    assert!(cpu.mem_read_byte(0x7F0E) == 0x26);
+    assert!(cpu.mem_read_byte(0x7F0D) == 0x10);
+}
+
+#[test]
+fn test_apu_square_wave_samples() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let mut instructions = vec![
+        0xa9, 0xb8, // mov $a, 0xb8
+        0x8d, 0x10, 0x7f, // mov byte ptr [0x7F10], $a
+        0xa9, 0x01, // mov $a, 0x01
+        0x8d, 0x11, 0x7f, // mov byte ptr [0x7F11], $a (440 Hz)
+        0xa9, 0x02, // mov $a, 0x02
+        0x8d, 0x12, 0x7f, // mov byte ptr [0x7F12], $a (50% duty)
+        0xa9, 0x0f, // mov $a, 0x0f
+        0x8d, 0x13, 0x7f, // mov byte ptr [0x7F13], $a (max volume)
+    ];
+
+    instructions.resize(instructions.len() + 0x1000, 0xea);
+    instructions.push(0x00);
+
+    cpu.reset();
+    cpu.bus_mut().apu_mut().set_enabled(true);
+    cpu.load(instructions);
+    cpu.run();
+
+    let samples = cpu.bus_mut().apu_mut().take_samples();
+    cpu.bus_mut().apu_mut().set_enabled(false);
+
+    // 0x1000 NOPs take 8192 cycles, which is ~361 samples at 44.1 kHz
+    assert!(samples.len() > 350 && samples.len() < 370);
+    assert!(samples.iter().any(|s| *s > 0));
+    assert!(samples.iter().any(|s| *s < 0));
+}
+
+#[test]
+fn test_branch_cycles() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa2, 0x02, // 0x8000: mov $x, 0x02
+        0xca, // 0x8002: dec $x
+        0xd0, 0xfd, // 0x8003: jnz 0x8002
+        0x00,
+    ]);
+    cpu.run();
+
+    // LDX (2) + 2 * DEX (2) + taken BNE (3) + not taken BNE (2)
+    assert!(cpu.cycles() == 2 + 2 * 2 + 3 + 2);
+}
+
+#[test]
+fn test_page_cross_cycles() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.mem_write_word(0x20, 0x02F0);
+    cpu.mem_write_word(0x22, 0x0200);
+    cpu.load(vec![
+        0xa2, 0x01, // mov $x, 0x01
+        0xa0, 0x10, // mov $y, 0x10
+        0xbd, 0xff, 0x02, // mov $a, byte ptr [0x02FF + $x]
+        0xbd, 0x00, 0x02, // mov $a, byte ptr [0x0200 + $x]
+        0xb9, 0xf0, 0x02, // mov $a, byte ptr [0x02F0 + $y]
+        0xb1, 0x20, // mov $a, byte ptr [word ptr [0x20] + $y]
+        0xb1, 0x22, // mov $a, byte ptr [word ptr [0x22] + $y]
+        0x9d, 0xff, 0x02, // mov byte ptr [0x02FF + $x], $a
+        0x00,
+    ]);
+    cpu.run();
+
+    // Reads which cross a page take a cycle more, stores take the same time either way
+    assert!(cpu.cycles() == 2 + 2 + (4 + 1) + 4 + (4 + 1) + (5 + 1) + 5 + 5);
+}
+
+#[test]
+fn test_speaker_toggle_samples() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let mut instructions = vec![];
+    for _ in 0..0x100 {
+        instructions.extend(&[0xad, 0x30, 0x7f]); // mov $a, byte ptr [0x7F30]
+        instructions.resize(instructions.len() + 0x20, 0xea);
+    }
+
+    instructions.push(0x00);
+
+    cpu.reset();
+    cpu.bus_mut().apu_mut().set_enabled(true);
+    cpu.load(instructions);
+    cpu.run();
+
+    let samples = cpu.bus_mut().apu_mut().take_samples();
+    cpu.bus_mut().apu_mut().set_enabled(false);
+
+    assert!(samples.iter().any(|s| *s > 0));
+    assert!(samples.iter().any(|s| *s < 0));
+}
+
+#[test]
+fn test_audio_follows_clock_speed() {
+    use crate::bus::apu::{Apu, SAMPLE_RATE};
+
+    // A second of emulated time gives a second of samples, whatever the clock speed
+    for hz in [1_000_000, 2_000_000, 500_000] {
+        let mut apu = Apu::default();
+        apu.set_enabled(true);
+        apu.set_clock(hz);
+        for now in (200..=hz).step_by(200) {
+            apu.tick(200, now);
+        }
+
+        assert!(apu.take_samples().len() == SAMPLE_RATE as usize);
+    }
+}
+
+#[test]
+fn test_palette_registers() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x12, // mov $a, 0x12
+        0x8d, 0x46, 0x7f, // mov byte ptr [0x7F46], $a (color 2, red)
+        0x00,
+    ]);
+    cpu.run();
+
+    assert!(cpu.bus().palette().rgb(2) == [0x12, 0x00, 0x00]);
+    assert!(cpu.bus().palette().rgb(0x11) == [0xFF, 0xFF, 0xFF]);
+    assert!(cpu.mem_read_byte(0x7F6F) == 0xBB);
+
+    cpu.reset();
+    assert!(cpu.bus().palette().rgb(2) == [0x88, 0x00, 0x00]);
+}
+
+#[test]
+fn test_bitmap_mode_vram_banks() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x01, // mov $a, 0x01
+        0x8d, 0x80, 0x7f, // mov byte ptr [0x7F80], $a (128x128)
+        0xa9, 0x21, // mov $a, 0x21
+        0x8d, 0x00, 0x60, // mov byte ptr [0x6000], $a
+        0xa9, 0x01, // mov $a, 0x01
+        0x8d, 0x81, 0x7f, // mov byte ptr [0x7F81], $a (bank 1)
+        0xa9, 0x60, // mov $a, 0x60
+        0x8d, 0xff, 0x6f, // mov byte ptr [0x6FFF], $a
+        0x00,
+    ]);
+    cpu.run();
+
+    assert!(cpu.bus().display().vram()[0x0000] == 0x21);
+    assert!(cpu.bus().display().vram()[0x1FFF] == 0x60);
+
+    let mut frame = Frame::new();
+    assert!(frame.update(cpu.bus_mut()));
+    assert!((frame.width(), frame.height()) == (128, 128));
+    assert!(frame.pixels()[0..3] == [0x88, 0x00, 0x00]); // red
+    assert!(frame.pixels()[3..6] == [0xFF, 0xFF, 0xFF]); // white
+    assert!(frame.pixels()[(128 * 128 - 2) * 3..(128 * 128 - 1) * 3] == [0x00, 0x00, 0xAA]); // blue
+    assert!(!frame.update(cpu.bus_mut()));
+}
+
+#[test]
+fn test_text_mode_rendering() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x03, // mov $a, 0x03
+        0x8d, 0x80, 0x7f, // mov byte ptr [0x7F80], $a (text)
+        0xa9, 0x41, // mov $a, 'A'
+        0x8d, 0x01, 0x70, // mov byte ptr [0x7001], $a
+        0xa9, 0x62, // mov $a, 0x62 (red on blue)
+        0x8d, 0x01, 0x74, // mov byte ptr [0x7401], $a
+        0x00,
+    ]);
+    cpu.run();
+
+    let mut frame = Frame::new();
+    assert!(frame.update(cpu.bus_mut()));
+    assert!((frame.width(), frame.height()) == (320, 200));
+
+    let rgb = |x: usize, y: usize| &frame.pixels()[(y * 320 + x) * 3..(y * 320 + x) * 3 + 3];
+    assert!(rgb(8, 0) == [0x00, 0x00, 0xAA]); // background of 'A'
+    assert!(rgb(10, 0) == [0x88, 0x00, 0x00]); // top of 'A'
+    assert!(rgb(0, 0) == [0x00, 0x00, 0x00]); // blank cell
+}
+
+#[test]
+fn test_sprite_collisions() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0xff, // mov $a, 0xFF
+        0x8d, 0x00, 0x10, // mov byte ptr [0x1000], $a (solid top row)
+        0xa9, 0x01, // mov $a, 0x01
+        0x8d, 0xa5, 0x7f, // mov byte ptr [0x7FA5], $a (enable sprite 0)
+        0x8d, 0xad, 0x7f, // mov byte ptr [0x7FAD], $a (enable sprite 1)
+        0x8d, 0x28, 0x02, // mov byte ptr [0x0228], $a (VGA pixel 8, 1)
+        0xa9, 0x10, // mov $a, 0x10
+        0x8d, 0xa3, 0x7f, // mov byte ptr [0x7FA3], $a (sprite 0 pattern 0x1000)
+        0x8d, 0xab, 0x7f, // mov byte ptr [0x7FAB], $a (sprite 1 pattern 0x1000)
+        0xa9, 0x02, // mov $a, 0x02
+        0x8d, 0xa4, 0x7f, // mov byte ptr [0x7FA4], $a (sprite 0 red)
+        0xa9, 0x04, // mov $a, 0x04
+        0x8d, 0xa8, 0x7f, // mov byte ptr [0x7FA8], $a (sprite 1 at x = 4)
+        0xad, 0xe0, 0x7f, // mov $a, byte ptr [0x7FE0] (nothing latched yet)
+        0x85, 0x20, // mov byte ptr [0x20], $a
+        0x20, 0x41, 0x80, // call 0x8041
+        0xae, 0xe0, 0x7f, // mov $x, byte ptr [0x7FE0]
+        0xac, 0xe1, 0x7f, // mov $y, byte ptr [0x7FE1]
+        0xa9, 0x01, // mov $a, 0x01
+        0x8d, 0xa9, 0x7f, // mov byte ptr [0x7FA9], $a (sprite 1 at y = 1)
+        0xad, 0xe1, 0x7f, // mov $a, byte ptr [0x7FE1] (still the last frame's mask)
+        0x85, 0x21, // mov byte ptr [0x21], $a
+        0x20, 0x41, 0x80, // call 0x8041
+        0xad, 0xe1, 0x7f, // mov $a, byte ptr [0x7FE1]
+        0x00, 0x2c, 0x82, 0x7f, // bit byte ptr [0x7F82] (wait for the next vblank)
+        0x30, 0xfb, // js -0x05
+        0x2c, 0x82, 0x7f, // bit byte ptr [0x7F82]
+        0x10, 0xfb, // jns -0x05
+        0x60, // ret
+    ]);
+    cpu.run();
+
+    assert!(cpu.mem_read_byte(0x20) == 0x00);
+    assert!(*cpu.x() == 0x03);
+    assert!(*cpu.y() == 0x00);
+    assert!(cpu.mem_read_byte(0x21) == 0x00);
+    assert!(*cpu.a() == 0x02);
+
+    let mut frame = Frame::new();
+    frame.update(cpu.bus_mut());
+    assert!(frame.pixels()[0..3] == [0x88, 0x00, 0x00]);
+}
+
+#[test]
+fn test_vblank_nmi() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x16, // mov $a, 0x16
+        0x8d, 0xfa, 0xff, // mov byte ptr [0xFFFA], $a
+        0xa9, 0x80, // mov $a, 0x80
+        0x8d, 0xfb, 0xff, // mov byte ptr [0xFFFB], $a (NMI vector 0x8016)
+        0xa9, 0x02, // mov $a, 0x02
+        0x8d, 0x83, 0x7f, // mov byte ptr [0x7F83], $a (NMI on vblank)
+        0xa5, 0x10, // mov $a, byte ptr [0x10]
+        0xc9, 0x02, // cmp $a, 0x02
+        0xd0, 0xfa, // jnz -0x06
+        0x00, // brk
+        0xe6, 0x10, // inc byte ptr [0x10]
+        0x40, // rti
+    ]);
+    cpu.run();
+
+    assert!(cpu.mem_read_byte(0x10) == 2);
+    assert!(cpu.cycles() > CYCLES_PER_FRAME + VBLANK_START);
+    assert!(cpu.cycles() < CYCLES_PER_FRAME * 2);
+    assert!(cpu.mem_read_byte(0x7F82) == 0x80);
+}
+
+#[test]
+fn test_vga_page_flip() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x02, // mov $a, 0x02
+        0x8d, 0x00, 0x02, // mov byte ptr [0x0200], $a (red on page 0)
+        0xa9, 0x05, // mov $a, 0x05
+        0x8d, 0x00, 0x06, // mov byte ptr [0x0600], $a (green on page 1)
+        0x00,
+    ]);
+    cpu.run();
+
+    let mut frame = Frame::new();
+    frame.update(cpu.bus_mut());
+    assert!(frame.pixels()[0..3] == [0x88, 0x00, 0x00]);
+
+    cpu.mem_write_byte(0x7F84, 0x01);
+    assert!(frame.update(cpu.bus_mut()));
+    assert!(frame.pixels()[0..3] == [0x00, 0xCC, 0x55]);
+}
+
+#[test]
+fn test_vga_write_tracking() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x05, // mov $a, 0x05
+        0x8d, 0x21, 0x02, // mov byte ptr [0x0221], $a
+        0x8d, 0x00, 0x06, // mov byte ptr [0x0600], $a
+        0x00,
+    ]);
+    cpu.run();
+
+    assert!(cpu.bus_mut().take_vga_writes() == vec![0x0221, 0x0600]);
+    assert!(cpu.bus_mut().take_vga_writes().is_empty());
+
+    let mut frame = Frame::new();
+    assert!(frame.update(cpu.bus_mut()));
+    assert!(!frame.update(cpu.bus_mut()));
+
+    cpu.mem_write_byte(0x0221, 0x02);
+    assert!(frame.update(cpu.bus_mut()));
+    assert!(frame.pixels()[33 * 3..33 * 3 + 3] == [0x88, 0x00, 0x00]);
+}
+
+#[test]
+fn test_screenshot_and_gif_recording() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let dir = std::env::temp_dir().join("yanes_test_capture");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    cpu.reset();
+    let mut frame = Frame::new();
+    let mut recorder = Recorder::new(&dir.join("session.gif"), 2);
+    for color in 0..3 {
+        cpu.mem_write_byte(0x0200, color);
+        let changed = frame.update(cpu.bus_mut());
+        recorder.record(&frame, changed).unwrap();
+        recorder.record(&frame, false).unwrap();
+    }
+
+    recorder.finish().unwrap();
+    save_png(&frame, 1, &dir.join("screen.png")).unwrap();
+
+    let gif = std::fs::read(dir.join("session.gif")).unwrap();
+    let png = std::fs::read(dir.join("screen.png")).unwrap();
+    assert!(gif.starts_with(b"GIF89a"));
+    assert!(gif[6..10] == [64, 0, 64, 0]); // 64x64 logical screen
+    assert!(png.starts_with(b"\x89PNG"));
+}
+
+#[test]
+fn test_soft_reset_keeps_memory() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xe6, 0x10, // inc byte ptr [0x10]
+        0xa9, 0x07, // mov $a, 0x07
+        0x8d, 0x00, 0x7f, // mov byte ptr [0x7F00], $a
+    ]);
+    cpu.run();
+    assert!(cpu.exit_code() == Some(0x07));
+
+    cpu.soft_reset();
+    assert!(*cpu.pc() == 0x8000);
+    assert!(*cpu.a() == 0x00);
+    assert!(cpu.exit_code().is_none());
+
+    cpu.run();
+    assert!(cpu.mem_read_byte(0x10) == 2);
+}
+
+#[test]
+fn test_peek_has_no_side_effects() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.bus_mut().rng_mut().set_mode(RngMode::Seeded(42));
+    cpu.reset();
+    let first = cpu.mem_read_byte(0x7F01);
+
+    cpu.reset();
+    assert!(cpu.bus().peek(0x7F01) == 0);
+    assert!(cpu.mem_read_byte(0x7F01) == first);
+
+    cpu.mem_write_byte(0x0300, 0x42);
+    assert!(cpu.bus().peek(0x0300) == 0x42);
+}
+
+#[test]
+fn test_disassembly() {
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x05, // mov $a, 0x05
+        0x9d, 0x00, 0x02, // mov byte ptr [0x0200 + $x], $a
+        0xb1, 0x10, // mov $a, byte ptr [[0x10] + $y]
+        0xd0, 0xf7, // jnz -0x09
+        0x0a, // shl $a
+    ]);
+
+    let bus = cpu.bus();
+    assert!(disassemble(bus, 0x8000) == (String::from("LDA #$05"), 2));
+    assert!(disassemble(bus, 0x8002) == (String::from("STA $0200,X"), 3));
+    assert!(disassemble(bus, 0x8005) == (String::from("LDA ($10),Y"), 2));
+    assert!(disassemble(bus, 0x8007) == (String::from("BNE $8000"), 2));
+    assert!(disassemble(bus, 0x8009) == (String::from("ASL"), 1));
+
+    let addrs = instructions_around(bus, 0x8007, 2, 4);
+    assert!(addrs == vec![0x8002, 0x8005, 0x8007, 0x8009]);
+}
+
+#[test]
+fn test_shifted_keycodes() {
+    assert!(keyboard::keycode(b'a', true) == b'A');
+    assert!(keyboard::keycode(b'1', true) == b'!');
+    assert!(keyboard::keycode(b'A', true) == b'A'); // already shifted by a terminal
+    assert!(keyboard::keycode(b'a', false) == b'a');
+}
+
+#[test]
+fn test_gdb_stub() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = |packet: &str| {
+            let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+
+            let mut reply = vec![];
+            let mut byte = [0];
+            while reply.last() != Some(&b'#') {
+                stream.read_exact(&mut byte).unwrap();
+                if !(reply.is_empty() && byte[0] == b'+') {
+                    reply.push(byte[0]);
+                }
+            }
+
+            stream.read_exact(&mut [0; 2]).unwrap();
+            stream.write_all(b"+").unwrap();
+            String::from_utf8(reply[1..reply.len() - 1].to_vec()).unwrap()
+        };
+
+        let replies = vec![
+            request("Z0,8004,1"),
+            request("c"),
+            request("g"),
+            request("P0=2a"),
+            request("Z2,0020,1"),
+            request("s"),
+            request("m0010,2"),
+            request("M0020,1:ff"),
+            request("s"),
+            request("c"),
+        ];
+
+        stream.write_all(b"$k#6b").unwrap();
+        replies
+    });
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x05, // mov $a, 0x05
+        0xa2, 0x07, // mov $x, 0x07
+        0x85, 0x10, // mov byte ptr [0x10], $a
+        0x86, 0x11, // mov byte ptr [0x11], $x
+        0x00,
+    ]);
+    crate::gdb::serve(&mut cpu, listener).unwrap();
+
+    let replies = client.join().unwrap();
+    assert!(replies[0] == "OK");
+    assert!(replies[1] == "S05");
+    assert!(replies[2].starts_with("0507") && replies[2].ends_with("ff010480"));
+    assert!(replies[3] == "OK" && replies[4] == "OK");
+    assert!(replies[5] == "S05");
+    assert!(replies[6] == "2a00");
+    // Memory written by GDB doesn't trigger the watchpoint on the next instruction
+    assert!(replies[7] == "OK" && replies[8] == "S05");
+    assert!(replies[9] == "W00");
+}
+
+#[test]
+fn test_gdb_stub_while_running() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let program = vec![
+        0xe8, // inc $x
+        0xd0, 0xfd, // jnz -3
+        0xc8, // inc $y
+        0xd0, 0xfa, // jnz -6
+        0x00,
+    ];
+
+    // A packet sent while the program runs is answered once it stops
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let timeout = std::time::Duration::from_secs(10);
+        stream.set_read_timeout(Some(timeout)).unwrap();
+        stream.write_all(b"$c#63$g#67").unwrap();
+
+        let mut replies = String::new();
+        let mut byte = [0];
+        while replies.matches('#').count() < 2 {
+            stream.read_exact(&mut byte).unwrap();
+            replies.push(byte[0] as char);
+        }
+
+        stream.read_exact(&mut [0; 2]).unwrap();
+
+        stream.write_all(b"+$k#6b").unwrap();
+        replies
+    });
+
+    cpu.reset();
+    cpu.load(program.clone());
+    crate::gdb::serve(&mut cpu, listener).unwrap();
+
+    let replies = client.join().unwrap();
+    assert!(replies.starts_with("+$W00#") && replies.contains("+$0000"));
+
+    // Disconnecting while the program runs stops it
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"$c#63").unwrap();
+    });
+
+    cpu.reset();
+    cpu.load(vec![
+        0x4c, 0x00, 0x80, // jmp 0x8000
+    ]);
+    // Depending on timing, the ack of the packet may fail to arrive too
+    assert!(crate::gdb::serve(&mut cpu, listener).is_err());
+    client.join().unwrap();
+}
+
+#[test]
+fn test_dap_server() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use serde_json::{json, Value};
+
+    use crate::debug_info::DebugInfo;
+
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let project = std::env::temp_dir().join("yanes_test_dap");
+    std::fs::create_dir_all(project.join("build")).unwrap();
+    std::fs::write(project.join("main.s"), "").unwrap();
+    std::fs::write(
+        project.join("build/main.dbg"),
+        "# main.s\n\
+         8000 start\n8000 ../main.s:1\n8002 ../main.s:2\n8005 ../main.s:3\n8007 ../main.s:4\n\
+         8008 sub\n8008 ../main.s:6\n8009 ../main.s:7\n",
+    )
+    .unwrap();
+    let main = std::fs::canonicalize(project.join("main.s")).unwrap();
+    let source_path = main.to_string_lossy().into_owned();
+
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut seq = 0;
+
+        let mut request = |command: &str, arguments: Value| {
+            seq += 1;
+            let body = json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+                .to_string();
+            write!(stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        };
+
+        // Skips messages until one with the given command or event name arrives
+        let mut receive = |name: &str| loop {
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                match header.trim().strip_prefix("Content-Length: ") {
+                    Some(value) => length = value.parse().unwrap(),
+                    None if header.trim().is_empty() => break,
+                    None => {}
+                }
+            }
+
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let message: Value = serde_json::from_slice(&body).unwrap();
+            if message["command"] == name || message["event"] == name {
+                return message;
+            }
+        };
+
+        let mut replies = vec![];
+        request("initialize", json!({ "adapterID": "yanes" }));
+        replies.push(receive("initialized"));
+        request("launch", json!({}));
+        let source = json!({ "path": source_path });
+        request(
+            "setBreakpoints",
+            json!({ "source": source, "breakpoints": [{ "line": 2 }, { "line": 5 }] }),
+        );
+        replies.push(receive("setBreakpoints"));
+        request("configurationDone", json!({}));
+        replies.push(receive("stopped"));
+        request("stackTrace", json!({ "threadId": 1 }));
+        replies.push(receive("stackTrace"));
+        request("next", json!({ "threadId": 1 }));
+        replies.push(receive("stopped"));
+        request("stepOut", json!({ "threadId": 1 }));
+        replies.push(receive("stopped"));
+        request("stackTrace", json!({ "threadId": 1 }));
+        replies.push(receive("stackTrace"));
+        request("variables", json!({ "variablesReference": 1 }));
+        replies.push(receive("variables"));
+        request(
+            "readMemory",
+            json!({ "memoryReference": "0x8000", "count": 3 }),
+        );
+        replies.push(receive("readMemory"));
+        request(
+            "disassemble",
+            json!({ "memoryReference": "0x8002", "instructionOffset": -1, "instructionCount": 3 }),
+        );
+        replies.push(receive("disassemble"));
+        request("continue", json!({ "threadId": 1 }));
+        replies.push(receive("exited"));
+        request("disconnect", json!({}));
+        replies.push(receive("disconnect"));
+        replies
+    });
+
+    let debug_info = DebugInfo::load(&project.join("build/main.dbg")).unwrap();
+    // Files with the same name elsewhere don't match
+    assert!(debug_info
+        .addresses(&project.join("build/main.s"), 1)
+        .is_none());
+    let addresses = debug_info.addresses(&project.join("build/../main.s"), 2);
+    assert!(addresses == Some((2, vec![0x8002])));
+
+    cpu.reset();
+    cpu.load(vec![
+        0xa9, 0x05, // mov $a, 0x05
+        0x20, 0x08, 0x80, // call 0x8008
+        0x85, 0x10, // mov byte ptr [0x10], $a
+        0x00, //
+        0xe8, // inc $x
+        0x60, // ret
+    ]);
+    crate::dap::serve(&mut cpu, listener, debug_info).unwrap();
+
+    let replies = client.join().unwrap();
+    let breakpoints = &replies[1]["body"]["breakpoints"];
+    assert!(breakpoints[0]["verified"] == true && breakpoints[0]["line"] == 2);
+    assert!(breakpoints[1]["verified"] == true && breakpoints[1]["line"] == 6);
+    assert!(replies[2]["body"]["reason"] == "breakpoint");
+
+    let frame = &replies[3]["body"]["stackFrames"][0];
+    assert!(frame["name"] == "start+2" && frame["line"] == 2);
+    assert!(frame["source"]["path"] == main.to_string_lossy().as_ref());
+
+    // Step Over stops at the breakpoint inside the subroutine
+    assert!(replies[4]["body"]["reason"] == "breakpoint");
+    assert!(replies[5]["body"]["reason"] == "step");
+    assert!(replies[6]["body"]["stackFrames"][0]["line"] == 3);
+
+    let variables = replies[7]["body"]["variables"].as_array().unwrap();
+    assert!(variables
+        .iter()
+        .any(|var| var["name"] == "A" && var["value"] == "$05"));
+    assert!(variables
+        .iter()
+        .any(|var| var["name"] == "X" && var["value"] == "$01"));
+    assert!(variables
+        .iter()
+        .any(|var| var["name"] == "PC" && var["value"] == "$8005"));
+
+    assert!(replies[8]["body"]["data"] == "qQUg");
+
+    let instructions = replies[9]["body"]["instructions"].as_array().unwrap();
+    assert!(instructions.len() == 3);
+    assert!(instructions[0]["address"] == "0x8000" && instructions[0]["instruction"] == "LDA #$05");
+    assert!(instructions[0]["symbol"] == "start" && instructions[0]["line"] == 1);
+    assert!(instructions[1]["instruction"] == "JSR $8008");
+    assert!(instructions[1]["instructionBytes"] == "20 08 80");
+    assert!(instructions[2]["address"] == "0x8005" && instructions[2]["line"] == 3);
+
+    assert!(replies[10]["body"]["exitCode"] == 0);
+    assert!(replies[11]["success"] == true);
+}
+
+#[test]
+fn test_debug_info_cc65() {
+    use std::path::Path;
+
+    use crate::debug_info::DebugInfo;
+
+    // `ca65 -g main.s && ld65 -C yanes.cfg --dbgfile main.dbg main.o`, with a macro used on
+    // line 6
+    let debug_info = DebugInfo::parse(
+        "version\tmajor=2,minor=0\n\
+         info\tcsym=0,file=2,lib=0,line=6,mod=1,scope=1,seg=1,span=4,sym=2,type=0\n\
+         file\tid=0,name=\"main.s\",size=96,mtime=0x60046B3A,mod=0\n\
+         file\tid=1,name=\"macros, common.inc\",size=40,mtime=0x60046B3A,mod=0\n\
+         line\tid=0,file=0,line=3,span=0\n\
+         line\tid=1,file=0,line=4,span=1\n\
+         line\tid=2,file=1,line=2,type=2,count=1,span=2+3\n\
+         line\tid=3,file=0,line=6,span=2+3\n\
+         line\tid=4,file=0,line=1\n\
+         mod\tid=0,name=\"main.o\",file=0\n\
+         seg\tid=0,name=\"CODE\",start=0x008000,size=0x0008,addrsize=absolute,type=ro,oname=\"main.bin\",ooffs=0\n\
+         span\tid=0,seg=0,start=0,size=2\n\
+         span\tid=1,seg=0,start=2,size=3\n\
+         span\tid=2,seg=0,start=5,size=2\n\
+         span\tid=3,seg=0,start=7,size=1\n\
+         scope\tid=0,name=\"\",mod=0,size=8,span=0+1+2+3\n\
+         sym\tid=0,name=\"start\",addrsize=absolute,scope=0,def=0,ref=1,val=0x8000,seg=0,type=lab\n\
+         sym\tid=1,name=\"COLOR\",addrsize=zeropage,scope=0,def=2,val=0x5,type=equ\n",
+    )
+    .unwrap();
+
+    assert!(debug_info.location(0x8000) == Some((Path::new("main.s"), 3)));
+    assert!(debug_info.location(0x8002) == Some((Path::new("main.s"), 4)));
+    // The bytes of a macro belong to the line using it
+    assert!(debug_info.location(0x8005) == Some((Path::new("main.s"), 6)));
+    assert!(debug_info.location(0x8007) == Some((Path::new("main.s"), 6)));
+    assert!(debug_info.addresses(Path::new("main.s"), 5) == Some((6, vec![0x8005, 0x8007])));
+    assert!(debug_info.describe(0x8002) == "start+2");
+    assert!(debug_info.describe(0x0005) == "$0005");
+
+    assert!(
+        DebugInfo::parse("version\tmajor=2,minor=0\nline\tid=0,file=0,line=1,span=0\n").is_err()
+    );
+}
+
+#[test]
+fn test_dap_rejects_long_messages() {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::debug_info::DebugInfo;
+
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    write!(stream, "Content-Length: 99999999999\r\n\r\n").unwrap();
+
+    cpu.reset();
+    cpu.load(vec![0x00]);
+    let result = crate::dap::serve(&mut cpu, listener, DebugInfo::default());
+    assert!(result.unwrap_err().kind() == std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_debugger_breakpoints_and_watchpoints() {
+    use crate::debugger::expression::Expression;
+    use crate::debugger::{Breakpoint, Debugger, HitCondition, LogMessage, Stop, Trigger};
+
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    cpu.reset();
+    cpu.load(vec![
+        0xa2, 0x00, // mov $x, 0x00
+        0xa9, 0x30, // mov $a, 0x30
+        0xe8, // inc $x
+        0x86, 0x10, // mov byte ptr [0x10], $x
+        0xe0, 0x0a, // cmp $x, 0x0a
+        0xd0, 0xf9, // jne -7
+        0xa5, 0x10, // mov $a, byte ptr [0x10]
+        0x00,
+    ]);
+
+    let mut debugger = Debugger::default();
+    let mut conditional = Breakpoint::at(0x8005);
+    conditional.condition = Some(Expression::parse("A == $30 && X > 5").unwrap());
+    let mut logging = Breakpoint::at(0x8007);
+    logging.log = Some(LogMessage::parse("x={X}").unwrap());
+    let mut write = Breakpoint::new(Trigger::Write, 0x10, 0x10);
+    write.hit_condition = Some(HitCondition::parse("== 8").unwrap());
+
+    assert!(debugger.add(conditional) == 1);
+    assert!(debugger.add(logging) == 2);
+    assert!(debugger.add(write) == 3);
+    assert!(debugger.add(Breakpoint::new(Trigger::Read, 0x0f, 0x11)) == 4);
+
+    let mut stops = vec![];
+    loop {
+        match debugger.step(&mut cpu) {
+            Some(Stop::Break(reason)) => {
+                stops.push((reason.id, reason.event, reason.addr, *cpu.x()))
+            }
+            Some(Stop::Halted) => break,
+            None => {}
+        }
+    }
+
+    assert!(
+        stops
+            == vec![
+                (1, Trigger::Execute, 0x8005, 6),
+                (1, Trigger::Execute, 0x8005, 7),
+                (1, Trigger::Execute, 0x8005, 8),
+                (3, Trigger::Write, 0x10, 8),
+                (1, Trigger::Execute, 0x8005, 9),
+                (1, Trigger::Execute, 0x8005, 10),
+                (4, Trigger::Read, 0x10, 10),
+            ]
+    );
+
+    let logs = debugger.take_logs();
+    assert!(logs.len() == 10 && logs[0] == "x=$01" && logs[9] == "x=$0A");
+    assert!(debugger.breakpoint(3).unwrap().hits() == 10);
+
+    assert!(Expression::parse("A ==").is_err());
+    assert!(Expression::parse("([$10] != 0").is_err());
+    assert!(HitCondition::parse("% 0").is_err());
+}
+
+#[test]
+fn test_debugger_access_watchpoint() {
+    use crate::debugger::{Breakpoint, Debugger, Stop, Trigger};
+
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    cpu.reset();
+    cpu.load(vec![
+        0x85, 0x10, // mov byte ptr [0x10], $a
+        0xa6, 0x10, // mov $x, byte ptr [0x10]
+        0x00,
+    ]);
+
+    let mut debugger = Debugger::default();
+    debugger.add(Breakpoint::new(Trigger::Access, 0x10, 0x10));
+
+    let mut stops = vec![];
+    loop {
+        match debugger.step(&mut cpu) {
+            Some(Stop::Break(reason)) => stops.push(reason.to_string()),
+            Some(Stop::Halted) => break,
+            None => {}
+        }
+    }
+
+    assert!(
+        stops
+            == vec![
+                "Access watchpoint 1 (write) at $0010",
+                "Access watchpoint 1 (read) at $0010"
+            ]
+    );
+}
+
+#[test]
+fn test_debugger_hits_once_per_instruction() {
+    use crate::debugger::{Breakpoint, Debugger, HitCondition, LogMessage, Stop, Trigger};
+
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    cpu.reset();
+    cpu.load(vec![
+        0xa0, 0x00, // mov $y, 0x00
+        0xe6, 0x10, // inc byte ptr [0x10]
+        0xe6, 0x10, // inc byte ptr [0x10]
+        0xe6, 0x10, // inc byte ptr [0x10]
+        0xb1, 0x10, // mov $a, byte ptr [word ptr [0x10] + $y]
+        0x00,
+    ]);
+
+    let mut debugger = Debugger::default();
+    let mut logging = Breakpoint::new(Trigger::Access, 0x10, 0x11);
+    logging.log = Some(LogMessage::parse("{[$10]}").unwrap());
+    let mut write = Breakpoint::new(Trigger::Write, 0x10, 0x10);
+    write.hit_condition = Some(HitCondition::parse("== 2").unwrap());
+    debugger.add(logging);
+    debugger.add(write);
+
+    let mut stops = vec![];
+    loop {
+        match debugger.step(&mut cpu) {
+            Some(Stop::Break(reason)) => stops.push((reason.id, *cpu.pc())),
+            Some(Stop::Halted) => break,
+            None => {}
+        }
+    }
+
+    // The reads and writes of INC, and the word read through the pointer, count once
+    assert!(stops == vec![(2, 0x8006)]);
+    assert!(debugger.take_logs() == vec!["$01", "$02", "$03", "$03"]);
+    assert!(debugger.breakpoint(1).unwrap().hits() == 4);
+    assert!(debugger.breakpoint(2).unwrap().hits() == 3);
+}
+
+#[test]
+fn test_call_stack() {
+    use crate::call_stack::FrameKind;
+
+    let mut cpu = CPU_SINGLETON.lock().ignore_poison();
+    cpu.reset();
+    cpu.load(vec![
+        0x20, 0x07, 0x80, // call 0x8007
+        0x20, 0x0c, 0x80, // call 0x800c
+        0x00, //
+        0x20, 0x0b, 0x80, // call 0x800b
+        0x60, // ret
+        0x60, // ret
+        0x68, // pop $a
+        0x68, // pop $a
+        0xa9, 0x80, // mov $a, 0x80
+        0x48, // push $a
+        0xa9, 0x06, // mov $a, 0x06
+        0x48, // push $a
+        0x60, // ret
+    ]);
+
+    while *cpu.pc() != 0x800b {
+        cpu.step();
+    }
+
+    let backtrace = cpu.call_stack().backtrace(*cpu.pc());
+    let levels = backtrace
+        .iter()
+        .map(|level| {
+            (
+                level.addr,
+                level
+                    .call
+                    .map(|call| (call.kind, call.entry, call.return_addr)),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert!(
+        levels
+            == vec![
+                (0x800b, None),
+                (0x8007, Some((FrameKind::Subroutine, 0x800b, 0x800a))),
+                (0x8000, Some((FrameKind::Subroutine, 0x8007, 0x8003))),
+            ]
+    );
+
+    cpu.run();
+    assert!(*cpu.pc() == 0x8007 && cpu.call_stack().depth() == 0);
+
+    let warnings = cpu.call_stack_mut().take_warnings();
+    assert!(
+        warnings
+            == vec![
+                "PLA at $800C removes the return address of the JSR at $8003 from the stack",
+                "RTS at $8014 returns to $8006 without a matching call",
+            ]
+    );
+}
@@ -0,0 +1,131 @@
+use std::env;
+use std::io::{self, Stdout, Write};
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, execute, queue, terminal};
+
+use crate::cpu::{MemLayout, RAMAccess, CPU};
+use crate::keyboard;
+use crate::screen::Frame;
+
+// Every character cell shows two pixels: the upper one in the foreground color
+// of this glyph, the lower one in the background color
+const UPPER_HALF_BLOCK: char = '\u{2580}';
+
+// Nearest color of the xterm 6x6x6 color cube
+fn ansi_256(rgb: &[u8]) -> u8 {
+    let level = |c: u8| ((c as u16 * 5 + 127) / 255) as u8;
+    16 + 36 * level(rgb[0]) + 6 * level(rgb[1]) + level(rgb[2])
+}
+
+// Raw mode terminal on the alternate screen, restored when dropped
+pub struct Terminal {
+    stdout: Stdout,
+    truecolor: bool,
+}
+
+impl Terminal {
+    pub fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+
+        let mut stdout = io::stdout();
+        execute!(
+            stdout,
+            terminal::EnterAlternateScreen,
+            cursor::Hide,
+            terminal::Clear(terminal::ClearType::All)
+        )?;
+
+        let truecolor = matches!(
+            env::var("COLORTERM").as_deref(),
+            Ok("truecolor") | Ok("24bit")
+        );
+
+        Ok(Terminal { stdout, truecolor })
+    }
+
+    fn color(&self, rgb: &[u8]) -> Color {
+        if self.truecolor {
+            Color::Rgb {
+                r: rgb[0],
+                g: rgb[1],
+                b: rgb[2],
+            }
+        } else {
+            Color::AnsiValue(ansi_256(rgb))
+        }
+    }
+
+    // Passes pressed keys to the program. Returns false once the user asked to quit with Escape or Ctrl+C.
+    pub fn handle_input(&mut self, cpu: &mut CPU) -> io::Result<bool> {
+        while event::poll(Duration::ZERO)? {
+            let key = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                _ => continue,
+            };
+
+            let charcode = match key.code {
+                KeyCode::Esc => return Ok(false),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(false)
+                }
+                KeyCode::Char(c) if c.is_ascii() => c as u8,
+                KeyCode::Enter => b'\r',
+                KeyCode::Backspace => 0x08,
+                KeyCode::Tab => b'\t',
+                KeyCode::Delete => 0x7F,
+                _ => continue,
+            };
+
+            let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+            cpu.mem_write_byte(MemLayout::KEYCODE_ADDR, keyboard::keycode(charcode, shift));
+        }
+
+        Ok(true)
+    }
+
+    pub fn draw(&mut self, frame: &Frame) -> io::Result<()> {
+        let pixel = |x: u32, y: u32| {
+            let i = (y * frame.width() + x) as usize * 3;
+            &frame.pixels()[i..i + 3]
+        };
+
+        for row in 0..frame.height().div_ceil(2) {
+            queue!(self.stdout, cursor::MoveTo(0, row as u16))?;
+            for x in 0..frame.width() {
+                let upper = pixel(x, row * 2);
+                let lower = if row * 2 + 1 < frame.height() {
+                    pixel(x, row * 2 + 1)
+                } else {
+                    upper
+                };
+
+                let (fg, bg) = (self.color(upper), self.color(lower));
+                queue!(
+                    self.stdout,
+                    SetForegroundColor(fg),
+                    SetBackgroundColor(bg),
+                    Print(UPPER_HALF_BLOCK)
+                )?;
+            }
+
+            queue!(self.stdout, ResetColor)?;
+        }
+
+        self.stdout.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = execute!(
+            self.stdout,
+            ResetColor,
+            cursor::Show,
+            terminal::LeaveAlternateScreen
+        );
+        let _ = terminal::disable_raw_mode();
+    }
+}